@@ -1,125 +1,538 @@
-use std::{collections::HashMap, sync::Mutex};
+use std::collections::HashMap;
 
-use lazy_static::lazy_static;
+use koopa::ir::{ValueKind, TypeKind, dfg::DataFlowGraph, entities::ValueData, BasicBlock, BinaryOp, Function, FunctionData, Program, Value};
 
-lazy_static! {
-    static ref MAP: Mutex<HashMap<Value,String>> = Mutex::new(HashMap::new());
-    static ref COUNT: Mutex<u32> = Mutex::new(0);
+use crate::regalloc::Allocator;
+
+/// 生成汇编时的全部状态, 每次编译都新建一个
+pub struct AsmContext<'p> {
+    program: &'p Program,
+    // 字符串字面量对应的全局变量和它们的编号, 标号用 .Lstr.编号, 不会和用户的全局变量重名
+    strings: HashMap<Value,usize>,
+    allocator: Allocator,
+    // 正在生成的函数
+    func: Option<Function>,
+    // 分到寄存器的值
+    regs: HashMap<Value,&'static str>,
+    // 溢出到栈上的值 (以及 alloc 出来的变量) 在栈帧中相对 sp 的偏移
+    slots: HashMap<Value,i32>,
+    // 用到的被调用者保存的寄存器, 以及它们保存在栈帧中的位置
+    saved: Vec<(&'static str,i32)>,
+    // 当前函数的栈帧大小
+    frame: i32,
+    // 当前函数调用了别的函数时, ra 保存在栈帧中的位置
+    ra_slot: Option<i32>,
 }
 
-// trait maptrait {
-//     fn get(&self, k: &Value) -> Option<&String>;
-//     fn insert(&self, k: Value, v:String);
+impl<'p> AsmContext<'p> {
+    pub fn new(program: &'p Program, strings: &[Value], allocator: Allocator) -> Self {
+        let strings=strings.iter().enumerate().map(|(i, &string)| (string, i)).collect();
+        AsmContext { program, strings, allocator, func: None, regs: HashMap::new(), slots: HashMap::new(), saved: Vec::new(), frame: 0, ra_slot: None }
+    }
+
+    fn func_data(&self) -> &'p FunctionData {
+        self.program.func(self.func.unwrap())
+    }
+
+    fn dfg(&self) -> &'p DataFlowGraph {
+        self.func_data().dfg()
+    }
+
+    /// 全局变量在汇编里的标号
+    fn global_name(&self, value: Value) -> String {
+        if let Some(i)=self.strings.get(&value) {
+            return format!(".Lstr.{}",i);
+        }
+        self.program.borrow_value(value).name().as_ref().unwrap()[1..].to_string()
+    }
+
+    /// 把 value 的值读到寄存器 reg 中, alloc 出来的变量读到的是它的地址
+    fn load_value(&self, reg: &str, value: Value) -> String {
+        if let Some(&r)=self.regs.get(&value) {
+            return if r==reg { String::new() } else { format!("  mv    {}, {}\n",reg,r) };
+        }
+        if value.is_global() {
+            return format!("  la    {}, {}\n",reg,self.global_name(value));
+        }
+        match self.dfg().value(value).kind() {
+            ValueKind::Integer(int) => format!("  li    {}, {}\n",reg,int.value()),
+            ValueKind::Alloc(_) => {
+                let offset=self.slots[&value];
+                if fits_imm(offset) {
+                    format!("  addi  {}, sp, {}\n",reg,offset)
+                }
+                else {
+                    format!("  li    {}, {}\n  add   {}, sp, {}\n",reg,offset,reg,reg)
+                }
+            }
+            // 第 8 个以后的参数在调用者的栈帧里
+            ValueKind::FuncArgRef(arg) if arg.index()>=8 => access_slot("lw", reg, self.frame+4*(arg.index() as i32-8), reg),
+            _ => access_slot("lw", reg, self.slots[&value], reg),
+        }
+    }
+
+    /// 拿到放着 value 的寄存器: 分到了寄存器就直接用, 否则先读到 scratch 里
+    fn operand<'a>(&self, value: Value, scratch: &'a str) -> (String, &'a str) {
+        if let Some(&reg)=self.regs.get(&value) {
+            return (String::new(), reg);
+        }
+        if self.integer(value)==Some(0) {
+            return (String::new(), "zero");
+        }
+        (self.load_value(scratch, value), scratch)
+    }
+
+    /// 计算 value 时结果写到哪个寄存器: 分到的寄存器, 溢出的值先写到 scratch 再存回栈上
+    fn dest<'a>(&self, value: Value, scratch: &'a str) -> &'a str {
+        self.regs.get(&value).copied().unwrap_or(scratch)
+    }
+
+    /// 值现在所在的寄存器或者栈上的位置. 常量, 全局变量和 alloc 不会被覆盖, 没有位置
+    fn location(&self, value: Value) -> Option<Location> {
+        if let Some(&reg)=self.regs.get(&value) {
+            return Some(Location::Reg(reg));
+        }
+        if value.is_global() || self.is_local_alloc(value) {
+            return None;
+        }
+        self.slots.get(&value).map(|&slot| Location::Slot(slot))
+    }
+
+    /// 跳到 target 前把实参赋给它的参数. 这些赋值是同时发生的, 要排好先后,
+    /// 只剩下互相等着的环时先把一个目标位置里的值挪到 t1
+    fn block_args(&self, target: BasicBlock, args: &[Value]) -> String {
+        // (参数, 实参), 实参为 None 表示已经挪到了 t1 里
+        let mut moves: Vec<(Value, Option<Value>)>=self.dfg().bb(target).params().iter().zip(args)
+            .filter(|&(&param, &arg)| self.location(param)!=self.location(arg))
+            .map(|(&param, &arg)| (param, Some(arg)))
+            .collect();
+        let mut ans=String::new();
+        while !moves.is_empty() {
+            let ready=moves.iter().position(|&(param, _)| {
+                moves.iter().all(|&(_, arg)| arg.and_then(|arg| self.location(arg))!=self.location(param))
+            });
+            let i=ready.unwrap_or_else(|| {
+                let dst=self.location(moves[0].0);
+                ans.push_str(&self.load_value("t1", moves[0].0));
+                for (_, arg) in moves.iter_mut() {
+                    if arg.and_then(|value| self.location(value))==dst {
+                        *arg=None;
+                    }
+                }
+                0
+            });
+            let (param, arg)=moves.remove(i);
+            match (arg, self.regs.get(&param)) {
+                (Some(arg), Some(&reg)) => ans.push_str(&self.load_value(reg, arg)),
+                (Some(arg), None) => {
+                    let (code, reg)=self.operand(arg, "t0");
+                    ans.push_str(&code);
+                    ans.push_str(&self.store_value(reg, param));
+                }
+                (None, _) => ans.push_str(&self.store_value("t1", param)),
+            }
+        }
+        ans
+    }
+
+    /// 基本块在汇编里的标号, 带上函数名以免不同函数的标号冲突
+    fn bb_label(&self, bb: BasicBlock) -> String {
+        let func_name=&self.program.func(self.func.unwrap()).name()[1..];
+        let bb_name=&self.dfg().bb(bb).name().as_ref().unwrap()[1..];
+        format!(".L{}.{}",func_name,bb_name)
+    }
+
+    /// 把寄存器 reg 的值写到 value 的位置, 分到的寄存器或者栈上
+    fn store_value(&self, reg: &str, value: Value) -> String {
+        match self.regs.get(&value) {
+            Some(&r) if r==reg => String::new(),
+            Some(&r) => format!("  mv    {}, {}\n",r,reg),
+            None => access_slot("sw", reg, self.slots[&value], "t2"),
+        }
+    }
+
+    /// getelemptr 和 getptr: 结果 = src + index * 结果指向的类型的大小
+    fn offset_ptr(&self, value: Value, src: Value, index: Value) -> String {
+        let size=match self.dfg().value(value).ty().kind() {
+            TypeKind::Pointer(elem) => elem.size(),
+            _ => unreachable!(),
+        };
+        let dst=self.dest(value, "t0");
+        let (src_code, src)=self.operand(src, "t0");
+        // 下标是常量时偏移直接算出来
+        let offset=self.integer(index).and_then(|index| index.checked_mul(size as i32)).filter(|&offset| fits_imm(offset));
+        let inst=match offset {
+            Some(offset) => format!("  addi  {}, {}, {}\n",dst,src,offset),
+            None => {
+                let (index_code, index)=self.operand(index, "t1");
+                format!("{}  li    t2, {}\n  mul   t1, {}, t2\n  add   {}, {}, t1\n",index_code,size,index,dst,src)
+            }
+        };
+        format!("{}{}{}",src_code,inst,self.store_value(dst, value))
+    }
+
+    /// value 是整数常量时返回它的值
+    fn integer(&self, value: Value) -> Option<i32> {
+        if value.is_global() {
+            return None;
+        }
+        match self.dfg().value(value).kind() {
+            ValueKind::Integer(int) => Some(int.value()),
+            _ => None,
+        }
+    }
 
-// }
+    /// 二元运算, 结果放在 dst.
+    /// 一边是能放进 12 位立即数的常量时尽量用带立即数的指令, 不用先 li 到寄存器里
+    fn binary(&self, op: BinaryOp, lhs: Value, rhs: Value, dst: &str) -> String {
+        // 常量在左边时, 可交换的运算交换两边, 比较则换成反过来的比较
+        let (op, lhs, rhs)=match (self.integer(lhs), self.integer(rhs)) {
+            (Some(_), None) => match op {
+                BinaryOp::Add | BinaryOp::Mul | BinaryOp::And | BinaryOp::Or |
+                BinaryOp::Xor | BinaryOp::Eq | BinaryOp::NotEq => (op, rhs, lhs),
+                BinaryOp::Lt => (BinaryOp::Gt, rhs, lhs),
+                BinaryOp::Gt => (BinaryOp::Lt, rhs, lhs),
+                BinaryOp::Le => (BinaryOp::Ge, rhs, lhs),
+                BinaryOp::Ge => (BinaryOp::Le, rhs, lhs),
+                _ => (op, lhs, rhs),
+            },
+            _ => (op, lhs, rhs),
+        };
+        let (lexpr, l)=self.operand(lhs, "t0");
+        if let Some(imm)=self.integer(rhs).and_then(|imm| binary_imm(op, imm, dst, l)) {
+            return format!("{}{}",lexpr,imm);
+        }
+        let (rexpr, r)=self.operand(rhs, "t1");
+        let (inst, fix)=match op {
+            BinaryOp::Add => ("add", None),
+            BinaryOp::Sub => ("sub", None),
+            BinaryOp::Mul => ("mul", None),
+            BinaryOp::Div => ("div", None),
+            BinaryOp::Mod => ("rem", None),
+            BinaryOp::And => ("and", None),
+            BinaryOp::Or => ("or", None),
+            BinaryOp::Xor => ("xor", None),
+            BinaryOp::Shl => ("sll", None),
+            BinaryOp::Shr => ("srl", None),
+            BinaryOp::Sar => ("sra", None),
+            BinaryOp::Lt => ("slt", None),
+            BinaryOp::Gt => ("sgt", None),
+            BinaryOp::Le => ("sgt", Some("seqz")),
+            BinaryOp::Ge => ("slt", Some("seqz")),
+            BinaryOp::Eq => ("xor", Some("seqz")),
+            BinaryOp::NotEq => ("xor", Some("snez")),
+        };
+        let mut ans=format!("{}{}  {:<6}{}, {}, {}\n",lexpr,rexpr,inst,dst,l,r);
+        if let Some(fix)=fix {
+            ans.push_str(&format!("  {:<6}{}, {}\n",fix,dst,dst));
+        }
+        ans
+    }
 
-// impl maptrait for Mutex<HashMap<Value,String>> {
-//     fn get(&self,k: &Value) -> Option<&String> {
-//         self.lock().unwrap().get(k)
-//     }
-//     fn insert(&self, k: Value, v:String) {
+    /// 指针 ptr 是不是当前函数栈帧里 alloc 出来的, 这时可以直接按 sp 寻址
+    fn is_local_alloc(&self, ptr: Value) -> bool {
+        !ptr.is_global() && matches!(self.dfg().value(ptr).kind(), ValueKind::Alloc(_))
+    }
 
-//     }
-// }
+    /// 从指针 ptr 指向的位置读值到 reg
+    fn load_from(&self, reg: &str, ptr: Value) -> String {
+        if self.is_local_alloc(ptr) {
+            access_slot("lw", reg, self.slots[&ptr], reg)
+        }
+        else {
+            let (code, ptr)=self.operand(ptr, reg);
+            format!("{}  lw    {}, 0({})\n",code,reg,ptr)
+        }
+    }
 
+    /// 把 reg 的值写到指针 ptr 指向的位置
+    fn store_to(&self, reg: &str, ptr: Value) -> String {
+        if self.is_local_alloc(ptr) {
+            access_slot("sw", reg, self.slots[&ptr], "t2")
+        }
+        else {
+            let (code, ptr)=self.operand(ptr, "t2");
+            format!("{}  sw    {}, 0({})\n",code,reg,ptr)
+        }
+    }
+}
 
 pub trait GenerateAsm {
-    fn generate(&self,dfg:Option<&DataFlowGraph>) -> String;
+    fn generate(&self, ctx: &mut AsmContext) -> String;
 }
 
-impl GenerateAsm for koopa::ir::Program {
-    fn generate(&self,_dfg:Option<&DataFlowGraph>) -> String {
-        let mut ans=String::from("  .text\n");
+impl GenerateAsm for Program {
+    fn generate(&self, ctx: &mut AsmContext) -> String {
+        let mut ans=String::new();
+        for &global in self.inst_layout() {
+            let value_data=self.borrow_value(global);
+            let name=ctx.global_name(global);
+            if let ValueKind::GlobalAlloc(alloc)=value_data.kind() {
+                // 字符串字面量只会被读, 放在 .rodata 里, 不用导出
+                if ctx.strings.contains_key(&global) {
+                    ans.push_str(&format!("  .section .rodata\n{}:\n{}",name,global_init(self, alloc.init())));
+                    continue;
+                }
+                let init=self.borrow_value(alloc.init());
+                let section=if matches!(init.kind(), ValueKind::ZeroInit(_)) { ".bss" } else { ".data" };
+                ans.push_str(&format!("  {}\n  .globl {}\n{}:\n{}",section,name,name,global_init(self, alloc.init())));
+            }
+        }
+        ans.push_str("  .text\n");
         for &func in self.func_layout() {
-            ans.push_str(self.func(func).generate(None).as_str());
+            // 运行时库里的函数只有声明, 没有函数体
+            if self.func(func).layout().entry_bb().is_none() {
+                continue;
+            }
+            ans.push_str(func.generate(ctx).as_str());
         }
         ans
     }
 }
 
-impl GenerateAsm for koopa::ir::FunctionData {
-    fn generate(&self,_dfg:Option<&DataFlowGraph>) -> String {
-        let mut ans=format!("  .globl {}\n{}:\n",&self.name()[1..],&self.name()[1..]);
-        
-        for (&_bb, node) in self.layout().bbs() {
-            
+impl GenerateAsm for Function {
+    fn generate(&self, ctx: &mut AsmContext) -> String {
+        ctx.func=Some(*self);
+        let func_data=ctx.program.func(*self);
+        let mut ans=format!("  .globl {}\n{}:\n",&func_data.name()[1..],&func_data.name()[1..]);
+
+        let alloc=ctx.allocator.allocate(func_data);
+
+        // 栈帧从低地址到高地址依次是: 传给被调用函数的第 8 个以后的参数,
+        // 每个 alloc, 溢出的值, 保存的被调用者保存的寄存器, 保存的 ra
+        let mut has_call=false;
+        let mut offset=0;
+        for (&_bb, node) in func_data.layout().bbs() {
+            for &inst in node.insts().keys() {
+                if let ValueKind::Call(call)=func_data.dfg().value(inst).kind() {
+                    has_call=true;
+                    offset=offset.max(4*(call.args().len() as i32-8));
+                }
+            }
+        }
+        ctx.slots.clear();
+        for (&_bb, node) in func_data.layout().bbs() {
+            for &inst in node.insts().keys() {
+                let value_data=func_data.dfg().value(inst);
+                if let ValueKind::Alloc(_)=value_data.kind() {
+                    ctx.slots.insert(inst, offset);
+                    offset+=alloc_size(value_data);
+                }
+            }
+        }
+        for &value in &alloc.spilled {
+            ctx.slots.insert(value, offset);
+            offset+=4;
+        }
+        ctx.saved.clear();
+        for reg in alloc.saved_regs() {
+            ctx.saved.push((reg, offset));
+            offset+=4;
+        }
+        ctx.regs=alloc.regs;
+        ctx.ra_slot=None;
+        if has_call {
+            ctx.ra_slot=Some(offset);
+            offset+=4;
+        }
+        // 栈帧按 16 字节对齐
+        ctx.frame=(offset+15)/16*16;
+        if ctx.frame>0 {
+            ans.push_str(&adjust_sp(-ctx.frame));
+        }
+        if let Some(ra_slot)=ctx.ra_slot {
+            ans.push_str(&access_slot("sw", "ra", ra_slot, "t2"));
+        }
+        for &(reg, slot) in &ctx.saved {
+            ans.push_str(&access_slot("sw", reg, slot, "t2"));
+        }
+        // 参数不会分到 a0-a7, 按顺序挪过去不会互相覆盖
+        for (i, &param) in func_data.params().iter().take(8).enumerate() {
+            ans.push_str(&ctx.store_value(&format!("a{}",i), param));
+        }
+
+        for (&bb, node) in func_data.layout().bbs() {
+            ans.push_str(&format!("{}:\n",ctx.bb_label(bb)));
             for &inst in node.insts().keys() {
-                ans.push_str(inst.generate(Some(self.dfg())).as_str());
+                ans.push_str(inst.generate(ctx).as_str());
             }
         }
         ans
     }
 }
 
+/// 值所在的位置
+#[derive(PartialEq, Clone, Copy)]
+enum Location {
+    Reg(&'static str),
+    Slot(i32),
+}
 
-use koopa::ir::{ValueKind, dfg::DataFlowGraph, BinaryOp,Value};
+/// 全局变量的初始值, 初始化列表里连续的 0 合并成一条 .zero
+fn global_init(program: &Program, init: Value) -> String {
+    let mut words=Vec::new();
+    flatten_init(program, init, &mut words);
+    let mut ans=String::new();
+    let mut zeros=0;
+    for word in words {
+        if word==0 {
+            zeros+=4;
+            continue;
+        }
+        if zeros>0 {
+            ans.push_str(&format!("  .zero {}\n",zeros));
+            zeros=0;
+        }
+        ans.push_str(&format!("  .word {}\n",word));
+    }
+    if zeros>0 {
+        ans.push_str(&format!("  .zero {}\n",zeros));
+    }
+    ans
+}
 
-impl GenerateAsm for koopa::ir::entities::Value {
-    fn generate(&self,dfg:Option<&DataFlowGraph>) -> String {
-        let value_data=dfg.unwrap().value(*self);
-        if let Some(_varname)=MAP.lock().unwrap().get(self) {
-            return String::from("");
+fn flatten_init(program: &Program, init: Value, words: &mut Vec<i32>) {
+    let init=program.borrow_value(init);
+    match init.kind() {
+        ValueKind::Integer(int) => words.push(int.value()),
+        ValueKind::ZeroInit(_) => words.resize(words.len()+init.ty().size()/4, 0),
+        ValueKind::Aggregate(aggr) => {
+            for &elem in aggr.elems() {
+                flatten_init(program, elem, words);
+            }
         }
+        _ => unreachable!(),
+    }
+}
+
+/// alloc 出来的变量在栈帧里占用的字节数
+fn alloc_size(value_data: &ValueData) -> i32 {
+    match value_data.ty().kind() {
+        TypeKind::Pointer(base) => base.size() as i32,
+        _ => unreachable!(),
+    }
+}
+
+/// 能不能放进 12 位有符号立即数
+fn fits_imm(imm: i32) -> bool {
+    (-2048..2048).contains(&imm)
+}
+
+/// 右边是常量 imm 时带立即数的指令, 左边在 src 里, 结果放在 dst; 没有合适的指令时为 None
+fn binary_imm(op: BinaryOp, imm: i32, dst: &str, src: &str) -> Option<String> {
+    // 和 0 比较相等时不用先异或
+    let cmp=|set: &str| if imm==0 {
+        format!("  {:<6}{}, {}\n",set,dst,src)
+    }
+    else {
+        format!("  xori  {}, {}, {}\n  {:<6}{}, {}\n",dst,src,imm,set,dst,dst)
+    };
+    let inst=match op {
+        BinaryOp::Add if fits_imm(imm) => format!("  addi  {}, {}, {}\n",dst,src,imm),
+        BinaryOp::Sub if imm!=i32::MIN && fits_imm(-imm) => format!("  addi  {}, {}, {}\n",dst,src,-imm),
+        BinaryOp::And if fits_imm(imm) => format!("  andi  {}, {}, {}\n",dst,src,imm),
+        BinaryOp::Or if fits_imm(imm) => format!("  ori   {}, {}, {}\n",dst,src,imm),
+        BinaryOp::Xor if fits_imm(imm) => format!("  xori  {}, {}, {}\n",dst,src,imm),
+        // 移位的立即数只能是 0 到 31
+        BinaryOp::Shl if (0..32).contains(&imm) => format!("  slli  {}, {}, {}\n",dst,src,imm),
+        BinaryOp::Shr if (0..32).contains(&imm) => format!("  srli  {}, {}, {}\n",dst,src,imm),
+        BinaryOp::Sar if (0..32).contains(&imm) => format!("  srai  {}, {}, {}\n",dst,src,imm),
+        BinaryOp::Lt if fits_imm(imm) => format!("  slti  {}, {}, {}\n",dst,src,imm),
+        BinaryOp::Ge if fits_imm(imm) => format!("  slti  {}, {}, {}\n  seqz  {}, {}\n",dst,src,imm,dst,dst),
+        // x <= c 就是 x < c+1, x > c 就是 !(x < c+1)
+        BinaryOp::Le if imm!=i32::MAX && fits_imm(imm+1) => format!("  slti  {}, {}, {}\n",dst,src,imm+1),
+        BinaryOp::Gt if imm!=i32::MAX && fits_imm(imm+1) => format!("  slti  {}, {}, {}\n  xori  {}, {}, 1\n",dst,src,imm+1,dst,dst),
+        BinaryOp::Eq if fits_imm(imm) => cmp("seqz"),
+        BinaryOp::NotEq if fits_imm(imm) => cmp("snez"),
+        _ => return None,
+    };
+    Some(inst)
+}
+
+/// 超出 12 位立即数范围时, 先把偏移放进 scratch 寄存器再寻址
+fn access_slot(op: &str, reg: &str, offset: i32, scratch: &str) -> String {
+    if fits_imm(offset) {
+        format!("  {:<6}{}, {}(sp)\n",op,reg,offset)
+    }
+    else {
+        format!("  li    {}, {}\n  add   {}, sp, {}\n  {:<6}{}, 0({})\n",scratch,offset,scratch,scratch,op,reg,scratch)
+    }
+}
+
+fn adjust_sp(size: i32) -> String {
+    if fits_imm(size) {
+        format!("  addi  sp, sp, {}\n",size)
+    }
+    else {
+        format!("  li    t0, {}\n  add   sp, sp, t0\n",size)
+    }
+}
+
+impl GenerateAsm for Value {
+    fn generate(&self, ctx: &mut AsmContext) -> String {
+        let value_data=ctx.dfg().value(*self);
         match value_data.kind() {
-            ValueKind::Integer(int) => {
-                match int.value() {
-                    0 => {
-                        MAP.lock().unwrap().insert(*self, String::from("x0"));
-                        String::from("")
-                    },
-                    _ => {
-                        let count = *COUNT.lock().unwrap();
-                        *COUNT.lock().unwrap() += 1;
-                        MAP.lock().unwrap().insert(*self, format!("t{}",count));
-                        format!("  li    {}, {}\n",MAP.lock().unwrap().get(self).unwrap(),int.value())
-                    }
-                }
+            ValueKind::Alloc(_) => String::new(),
+            ValueKind::Load(load) => {
+                let dst=ctx.dest(*self, "t0");
+                format!("{}{}",ctx.load_from(dst, load.src()),ctx.store_value(dst, *self))
+            }
+            ValueKind::GetElemPtr(gep) => ctx.offset_ptr(*self, gep.src(), gep.index()),
+            ValueKind::GetPtr(getptr) => ctx.offset_ptr(*self, getptr.src(), getptr.index()),
+            ValueKind::Store(store) => {
+                let (code, value)=ctx.operand(store.value(), "t0");
+                format!("{}{}",code,ctx.store_to(value, store.dest()))
+            }
+            ValueKind::Branch(branch) => {
+                // 条件跳转只能跳 4KiB 以内, 只用它跳过紧跟着的一条 j, 远的地方都用 j 去
+                let (code, cond)=ctx.operand(branch.cond(), "t0");
+                let skip=format!("{}.false",ctx.bb_label(ctx.func_data().layout().parent_bb(*self).unwrap()));
+                format!("{}  beqz  {}, {}\n{}  j     {}\n{}:\n{}  j     {}\n",code,cond,skip,
+                    ctx.block_args(branch.true_bb(), branch.true_args()),ctx.bb_label(branch.true_bb()),skip,
+                    ctx.block_args(branch.false_bb(), branch.false_args()),ctx.bb_label(branch.false_bb()))
+            }
+            ValueKind::Jump(jump) => {
+                format!("{}  j     {}\n",ctx.block_args(jump.target(), jump.args()),ctx.bb_label(jump.target()))
             }
             ValueKind::Return(ret) => {
-                if let Some(s)=MAP.lock().unwrap().get(&ret.value().unwrap()) {
-                    format!("  mv    a0, {}\n  ret",s)
+                let mut ans=match ret.value() {
+                    Some(value) => ctx.load_value("a0", value),
+                    None => String::new(),
+                };
+                for &(reg, slot) in &ctx.saved {
+                    ans.push_str(&access_slot("lw", reg, slot, reg));
                 }
-                else {
-                    if let ValueKind::Integer(v)=dfg.unwrap().value(ret.value().unwrap()).kind() {
-                        format!("  li    a0, {}\n  ret",v.value())
+                if let Some(ra_slot)=ctx.ra_slot {
+                    ans.push_str(&access_slot("lw", "ra", ra_slot, "ra"));
+                }
+                if ctx.frame>0 {
+                    ans.push_str(&adjust_sp(ctx.frame));
+                }
+                ans.push_str("  ret\n");
+                ans
+            }
+            ValueKind::Call(call) => {
+                // 前 8 个参数放在 a0-a7, 其余的从 sp 开始依次放在栈上
+                let mut ans=String::new();
+                for (i, &arg) in call.args().iter().enumerate() {
+                    if i<8 {
+                        ans.push_str(&ctx.load_value(&format!("a{}",i), arg));
                     }
                     else {
-                        format!("")
+                        let (code, arg)=ctx.operand(arg, "t0");
+                        ans.push_str(&code);
+                        ans.push_str(&access_slot("sw", arg, 4*(i as i32-8), "t2"));
                     }
                 }
-                // println!("{:#?}",MAP.lock().unwrap().get(&ret.value().unwrap()));
-                // format!("  mv    a0, {}\n  ret",MAP.lock().unwrap().get(&ret.value().unwrap()).unwrap())
-                
+                ans.push_str(&format!("  call  {}\n",&ctx.program.func(call.callee()).name()[1..]));
+                if !value_data.ty().is_unit() {
+                    ans.push_str(&ctx.store_value("a0", *self));
+                }
+                ans
             }
             ValueKind::Binary(op) => {
-                let count = *COUNT.lock().unwrap();
-                *COUNT.lock().unwrap() += 1;
-                MAP.lock().unwrap().insert(*self, format!("t{}",count));
-                let lval=op.lhs();
-                let rval=op.rhs();
-                let lexpr=lval.generate(dfg);
-                let rexpr=rval.generate(dfg);
-                let tmp=MAP.lock().unwrap();
-                let selfvar=tmp.get(self).unwrap();
-                let lvarstr=tmp.get(&lval).unwrap();
-                let rvarstr=tmp.get(&rval).unwrap();
-                match op.op() {
-                    BinaryOp::Sub => format!("{}{}  sub   {}, {}, {}\n",lexpr,rexpr,selfvar,lvarstr,rvarstr),
-                    BinaryOp::Xor => format!("{}{}  xor   {}, {}, {}\n",lexpr,rexpr,selfvar,lvarstr,rvarstr),
-                    BinaryOp::Eq => format!("{}{}  seqz  {}, {}\n",lexpr,rexpr,selfvar,rvarstr),
-                    BinaryOp::NotEq => format!("{}{}  snez  {}, {}\n",lexpr,rexpr,selfvar,rvarstr),
-                    BinaryOp::Add => format!("{}{}  add   {}, {}, {}\n",lexpr,rexpr,selfvar,lvarstr,rvarstr),
-                    BinaryOp::Mul => format!("{}{}  mul   {}, {}, {}\n",lexpr,rexpr,selfvar,lvarstr,rvarstr),
-                    BinaryOp::Div => format!("{}{}  mul   {}, {}, {}\n",lexpr,rexpr,selfvar,lvarstr,rvarstr),
-                    BinaryOp::Mod => format!("{}{}  mul   {}, {}, {}\n",lexpr,rexpr,selfvar,lvarstr,rvarstr),
-                    BinaryOp::Lt => format!("{}{}  slt   {}, {}, {}\n",lexpr,rexpr,selfvar,lvarstr,rvarstr),
-                    BinaryOp::Gt => format!("{}{}  sgt   {}, {}, {}\n",lexpr,rexpr,selfvar,lvarstr,rvarstr),
-                    BinaryOp::Le => format!("{}{}  sgt   {}, {}, {}\n  seqz  {}, {}\n",lexpr,rexpr,selfvar,lvarstr,rvarstr,selfvar,selfvar),
-                    BinaryOp::Ge => format!("{}{}  slt   {}, {}, {}\n  seqz  {}, {}\n",lexpr,rexpr,selfvar,lvarstr,rvarstr,selfvar,selfvar),
-                    BinaryOp::And => format!("{}{}  and   {}, {}, {}\n",lexpr,rexpr,selfvar,lvarstr,rvarstr),
-                    BinaryOp::Or => format!("{}{}  or    {}, {}, {}\n",lexpr,rexpr,selfvar,lvarstr,rvarstr),
-                    _ => unreachable!()
-                }
+                let dst=ctx.dest(*self, "t0");
+                format!("{}{}",ctx.binary(op.op(), op.lhs(), op.rhs(), dst),ctx.store_value(dst, *self))
             }
             _ => unreachable!()
         }
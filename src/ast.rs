@@ -1,6 +1,39 @@
+use std::fmt;
+
+/// 节点在源码中的字节范围 [start, end)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// 从 self 开头到 other 结尾的范围
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start, other.end)
+    }
+}
+
+/// 每个节点都能给出它在源码中的范围.
+/// 只包着一个子节点或者由左右两部分组成的节点不单独存, 由子节点算出来
+pub trait Spanned {
+    fn span(&self) -> Span;
+}
+
 #[derive(Debug)]
 pub struct CompUnit {
-  pub func_def: FuncDef,
+  pub items: Vec<GlobalItem>,
+  pub span: Span,
+}
+
+#[derive(Debug)]
+pub enum GlobalItem {
+    Decl(Decl),
+    FuncDef(FuncDef),
 }
 
 #[derive(Debug)]
@@ -12,6 +45,7 @@ pub enum Decl {
 #[derive(Debug)]
 pub struct ConstDecl {
     pub constdefs: Vec<ConstDef>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
@@ -22,45 +56,70 @@ pub enum BType {
 #[derive(Debug)]
 pub struct ConstDef {
     pub ident: String,
+    pub dims: Vec<Expr>,
     pub constinitval: ConstInitVal,
+    // 常量或常量数组的类型, 由 sema 填写
+    pub ty: Ty,
+    pub span: Span,
 }
 
 #[derive(Debug)]
-pub struct ConstInitVal {
-    pub constexp: ConstExp,
+pub enum ConstInitVal {
+    Exp(Expr),
+    List(Vec<ConstInitVal>, Span),
 }
 
 #[derive(Debug)]
 pub struct VarDecl {
     pub vardefs: Vec<VarDef>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub struct VarDef {
     pub ident: String,
+    pub dims: Vec<Expr>,
     pub initval: Option<InitVal>,
+    // 变量或数组的类型, 由 sema 填写
+    pub ty: Ty,
+    pub span: Span,
 }
 
 #[derive(Debug)]
-pub struct InitVal {
-    pub exp: Exp,
+pub enum InitVal {
+    Exp(Expr),
+    List(Vec<InitVal>, Span),
 }
 
 #[derive(Debug)]
 pub struct FuncDef {
   pub func_type: FuncType,
   pub ident: String,
+  pub params: Vec<FuncFParam>,
   pub block: Block,
+  pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct FuncFParam {
+    pub ident: String,
+    // 数组参数省略第一维, 记录后面每一维的长度; 普通参数为 None
+    pub dims: Option<Vec<Expr>>,
+    // 参数的类型, 数组参数为指针, 由 sema 填写
+    pub ty: Ty,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub enum FuncType{
     Int,
+    Void,
 }
 
 #[derive(Debug)]
 pub struct Block{
     pub items: Vec<BlockItem>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
@@ -71,31 +130,102 @@ pub enum BlockItem {
 
 #[derive(Debug)]
 pub enum Stmt {
-    Assign(LVal, Exp),
-    Return(Exp),
+    Assign(LVal, Expr, Span),
+    Exp(Option<Expr>, Span),
+    Return(Option<Expr>, Span),
+    Block(Block),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>, Span),
+    While(Expr, Box<Stmt>, Span),
+    Break(Span),
+    Continue(Span),
 }
 
+/// 表达式. 语法里按优先级分的各层在解析时就合并成了这一种节点
 #[derive(Debug)]
-pub struct Exp{
-    pub lorexp:Box<LOrExp>,
+pub struct Expr {
+    pub kind: ExprKind,
+    // 表达式的类型, 和名字有关的 (变量, 数组元素, 函数调用) 要查了符号表才知道,
+    // 先为 None, 由 sema 填写
+    pub ty: Option<Ty>,
+    pub span: Span,
 }
 
 #[derive(Debug)]
-pub struct LVal {
-    pub ident: String,
+pub enum ExprKind {
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Lit(i32),
+    // 字符串字面量, 只能作为 putf 的格式串, 保存引号里原样的内容
+    Str(String),
+    Var(String),
+    Call(String, Vec<Expr>),
+    // 带下标的数组元素, 下标按从外到内的顺序排列
+    Index(String, Vec<Expr>),
 }
 
-#[derive(Debug)]
-pub enum PrimaryExp {
-    Exp(Exp),
-    Number(i32),
-    LVal(LVal),
+impl Expr {
+    /// 新建一个表达式, 不依赖名字的类型在这里就填上
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        let ty=match &kind {
+            ExprKind::Binary(..) | ExprKind::Unary(..) | ExprKind::Lit(_) => Some(Ty::Int),
+            ExprKind::Str(_) => Some(Ty::Str),
+            ExprKind::Var(_) | ExprKind::Call(..) | ExprKind::Index(..) => None,
+        };
+        Expr { kind, ty, span }
+    }
+
+    pub fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Self {
+        let span=lhs.span.to(rhs.span);
+        Self::new(ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)), span)
+    }
+}
+
+/// 表达式和定义的类型
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ty {
+    Int,
+    /// void 函数调用的结果
+    Void,
+    Str,
+    /// 数组, 记录每一维的长度
+    Array(Vec<usize>),
+    /// 数组退化成的指针, 记录它指向的元素每一维的长度
+    Ptr(Vec<usize>),
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ty::Int => write!(f,"int"),
+            Ty::Void => write!(f,"void"),
+            Ty::Str => write!(f,"string"),
+            Ty::Array(dims) => {
+                write!(f,"int")?;
+                dims.iter().try_for_each(|dim| write!(f,"[{}]",dim))
+            }
+            Ty::Ptr(dims) => {
+                write!(f,"int[]")?;
+                dims.iter().try_for_each(|dim| write!(f,"[{}]",dim))
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
-pub enum UnaryExp {
-    PExp(PrimaryExp),
-    UExp(UnaryOp,Box<UnaryExp>),
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Or,
 }
 
 #[derive(Debug)]
@@ -106,70 +236,85 @@ pub enum UnaryOp {
     Inv,
 }
 
+/// 赋值语句的左边
 #[derive(Debug)]
-pub enum MulExp {
-    UExp(UnaryExp),
-    MExp(Box<MulExp>,MulOp,UnaryExp),
+pub struct LVal {
+    pub ident: String,
+    pub indices: Vec<Expr>,
+    pub span: Span,
 }
 
-#[derive(Debug)]
-pub enum MulOp {
-    Mul,
-    Div,
-    Mod,
+impl From<LVal> for Expr {
+    fn from(lval: LVal) -> Self {
+        let kind=if lval.indices.is_empty() { ExprKind::Var(lval.ident) } else { ExprKind::Index(lval.ident, lval.indices) };
+        Expr::new(kind, lval.span)
+    }
 }
 
-#[derive(Debug)]
-pub enum AddExp {
-    MExp(MulExp),
-    AExp(Box<AddExp>,AddOp,MulExp),
+// 自己存着 span 的节点
+macro_rules! impl_spanned {
+    ($($node: ty),*) => {
+        $(impl Spanned for $node {
+            fn span(&self) -> Span {
+                self.span
+            }
+        })*
+    };
 }
 
-#[derive(Debug)]
-pub enum AddOp {
-    Add,
-    Sub,
-}
+impl_spanned!(CompUnit, ConstDecl, ConstDef, VarDecl, VarDef, FuncDef, FuncFParam, Block, Expr, LVal);
 
-#[derive(Debug)]
-pub enum RelExp {
-    AExp(AddExp),
-    RExp(Box<RelExp>,RelOp,AddExp),
+impl Spanned for GlobalItem {
+    fn span(&self) -> Span {
+        match self {
+            Self::Decl(decl) => decl.span(),
+            Self::FuncDef(func_def) => func_def.span(),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum RelOp {
-    Lt,
-    Gt,
-    Le,
-    Ge,
+impl Spanned for Decl {
+    fn span(&self) -> Span {
+        match self {
+            Self::CDecl(constdecl) => constdecl.span(),
+            Self::VDecl(vardecl) => vardecl.span(),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum EqExp {
-    RExp(RelExp),
-    EExp(Box<EqExp>,EqOp,RelExp),
+impl Spanned for ConstInitVal {
+    fn span(&self) -> Span {
+        match self {
+            Self::Exp(exp) => exp.span,
+            Self::List(_, span) => *span,
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum EqOp {
-    Equ,
-    Ne,
+impl Spanned for InitVal {
+    fn span(&self) -> Span {
+        match self {
+            Self::Exp(exp) => exp.span,
+            Self::List(_, span) => *span,
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum LAndExp {
-    EExp(EqExp),
-    LAExp(Box<LAndExp>,EqExp),
+impl Spanned for BlockItem {
+    fn span(&self) -> Span {
+        match self {
+            Self::Decl(decl) => decl.span(),
+            Self::Stmt(stmt) => stmt.span(),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum LOrExp {
-    LAExp(LAndExp),
-    LOExp(Box<LOrExp>,LAndExp),
+impl Spanned for Stmt {
+    fn span(&self) -> Span {
+        match self {
+            Self::Block(block) => block.span,
+            Self::Assign(.., span) | Self::Exp(.., span) | Self::Return(.., span) |
+            Self::If(.., span) | Self::While(.., span) | Self::Break(span) | Self::Continue(span) => *span,
+        }
+    }
 }
-
-#[derive(Debug)]
-pub struct ConstExp {
-    pub exp: Exp,
-}
\ No newline at end of file
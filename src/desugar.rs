@@ -0,0 +1,39 @@
+use crate::ast::*;
+use crate::visit::{VisitorMut, walk_expr_mut};
+use std::convert::Infallible;
+
+/// 展开运行时库里用宏定义的函数.
+/// sylib.h 里 starttime() 和 stoptime() 是宏,
+/// 分别展开成带上所在行号的 _sysy_starttime(__LINE__) 和 _sysy_stoptime(__LINE__)
+pub struct ExpandMacros {
+    // 源码里每一行开头的字节偏移
+    lines: Vec<usize>,
+}
+
+impl ExpandMacros {
+    pub fn new(source: &str) -> Self {
+        let mut lines=vec![0];
+        lines.extend(source.match_indices('\n').map(|(i, _)| i+1));
+        ExpandMacros { lines }
+    }
+
+    /// 源码中字节偏移 offset 所在的行号, 从 1 开始
+    fn line(&self, offset: usize) -> usize {
+        self.lines.partition_point(|&start| start<=offset)
+    }
+}
+
+impl VisitorMut for ExpandMacros {
+    type Error = Infallible;
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) -> Result<(), Infallible> {
+        if let ExprKind::Call(ident, args)=&mut expr.kind {
+            // 带了参数的不展开, 由 sema 按 starttime 和 stoptime 不带参数报参数个数不对
+            if (ident=="starttime" || ident=="stoptime") && args.is_empty() {
+                *ident=format!("_sysy_{}",ident);
+                args.push(Expr::new(ExprKind::Lit(self.line(expr.span.start) as i32), expr.span));
+            }
+        }
+        walk_expr_mut(self, expr)
+    }
+}
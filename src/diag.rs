@@ -0,0 +1,64 @@
+use crate::ast::Span;
+use crate::lexer::Tok;
+use lalrpop_util::ParseError;
+use std::fmt;
+
+/// 一条带源码位置的错误信息
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl fmt::Display, span: Span) -> Self {
+        Diagnostic { message: message.to_string(), span }
+    }
+
+    /// 按 rustc 的格式输出: 错误信息, 文件名:行:列, 出错的那一行源码, 以及下面用 ^ 标出的范围
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let start=self.span.start.min(source.len());
+        let line_start=source[..start].rfind('\n').map_or(0, |i| i+1);
+        let line_end=source[start..].find('\n').map_or(source.len(), |i| start+i);
+        let line=source[line_start..line_end].trim_end_matches('\r');
+        let lineno=source[..start].matches('\n').count()+1;
+        let col=source[line_start..start].chars().count()+1;
+        // 下划线只画到这一行结尾, 至少一个 ^; 前面的 tab 原样保留以便对齐
+        let indent: String=line.chars().take(col-1).map(|c| if c=='\t' { '\t' } else { ' ' }).collect();
+        let end=self.span.end.clamp(start, line_start+line.len());
+        let width=source[start..end].chars().count().max(1);
+        let gutter=" ".repeat(lineno.to_string().len());
+        format!("error: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}\n",
+            self.message,gutter,file,lineno,col,gutter,lineno,line,gutter,indent,"^".repeat(width))
+    }
+}
+
+/// 把 lalrpop 报出的语法错误转成 Diagnostic
+pub fn from_parse_error(err: ParseError<usize, Tok, Diagnostic>) -> Diagnostic {
+    match err {
+        ParseError::InvalidToken { location } => Diagnostic::new("unrecognized token", Span::new(location, location+1)),
+        ParseError::UnrecognizedEOF { location, expected } => {
+            Diagnostic::new(format!("unexpected end of file, expected {}",expected_tokens(&expected)), Span::new(location, location))
+        }
+        ParseError::UnrecognizedToken { token: (l, token, r), expected } => {
+            Diagnostic::new(format!("unexpected `{}`, expected {}",token,expected_tokens(&expected)), Span::new(l, r))
+        }
+        ParseError::ExtraToken { token: (l, token, r) } => Diagnostic::new(format!("unexpected `{}`",token), Span::new(l, r)),
+        ParseError::User { error } => error,
+    }
+}
+
+/// lalrpop 给出的可能的 token 是文法里的写法, 标识符和字面量直接用名字, 其余的用反引号括起来
+fn expected_tokens(expected: &[String]) -> String {
+    let names: Vec<String>=expected.iter().map(|token| {
+        let name=token.trim_matches('"');
+        match name {
+            "identifier" | "integer literal" | "string literal" => name.to_string(),
+            _ => format!("`{}`",name),
+        }
+    }).collect();
+    match names.len() {
+        1 => names[0].clone(),
+        _ => format!("one of {}",names.join(", ")),
+    }
+}
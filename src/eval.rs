@@ -1,128 +1,163 @@
 use crate::ast::*;
+use crate::symtab::SymbolTable;
+use std::fmt;
 
-pub trait Evaluate {
-    fn eval(&self) -> Option<i32>;
+/// 常量求值失败的原因
+#[derive(Debug)]
+pub enum EvalError {
+    /// 表达式里有变量或函数调用, 编译期算不出来
+    NotConstant,
+    /// 除数或模数为 0, 记录这个除法在源码里的位置
+    DivisionByZero(Span),
+    /// 常量数组的下标越界, 记录这个下标在源码里的位置
+    IndexOutOfBounds(Span),
 }
 
-impl Evaluate for Exp {
-    fn eval(&self) -> Option<i32> {
-        self.lorexp.eval()
+impl EvalError {
+    /// 出错的具体位置, 没有时由调用的地方指出是哪个表达式算不出来
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            EvalError::NotConstant => None,
+            EvalError::DivisionByZero(span) | EvalError::IndexOutOfBounds(span) => Some(*span),
+        }
     }
 }
 
-impl Evaluate for PrimaryExp {
-    fn eval(&self) -> Option<i32> {
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Exp(exp) => exp.eval(),
-            Self::Number(number) => Some(*number),
-            _ => None, // 常量声明中，右边不允许出现左值
+            EvalError::NotConstant => write!(f,"not a constant expression"),
+            EvalError::DivisionByZero(_) => write!(f,"attempt to divide by zero"),
+            EvalError::IndexOutOfBounds(_) => write!(f,"index out of bounds"),
         }
     }
 }
 
-impl Evaluate for UnaryExp {
-    fn eval(&self) -> Option<i32> {
-        match self {
-            Self::PExp(primaryexp) => primaryexp.eval(),
-            Self::UExp(unaryop, unaryexp) => unaryexp.eval().map(|exp| match unaryop {
+pub type EvalResult = Result<i32, EvalError>;
+
+/// 符号表里记录的东西, 能告诉求值时一个名字是不是常量
+pub trait Constant {
+    /// 常量返回 (空的维度, [它的值]), 常量数组返回每一维的长度和按行优先展开的元素,
+    /// 其他名字返回 None
+    fn constant(&self) -> Option<(&[usize], &[i32])>;
+}
+
+pub trait Evaluate {
+    /// 在编译期求值, 表达式里的名字到 symbols 里查
+    fn eval<T: Constant>(&self, symbols: &SymbolTable<T>) -> EvalResult;
+}
+
+impl Evaluate for Expr {
+    fn eval<T: Constant>(&self, symbols: &SymbolTable<T>) -> EvalResult {
+        match &self.kind {
+            ExprKind::Lit(value) => Ok(*value),
+            ExprKind::Unary(op, exp) => exp.eval(symbols).map(|exp| match op {
                 UnaryOp::Inv => !exp,
-                UnaryOp::Neg => -exp,
+                UnaryOp::Neg => exp.wrapping_neg(),
                 UnaryOp::Not => (exp == 0) as i32,
                 UnaryOp::Pos => exp,
-            })
+            }),
+            ExprKind::Binary(op, lhs, rhs) => {
+                // && 和 || 短路: 左边已经能决定结果时不算右边, 右边除以 0 或下标越界都不算错
+                let lhs=lhs.eval(symbols)?;
+                match op {
+                    BinaryOp::And if lhs==0 => return Ok(0),
+                    BinaryOp::Or if lhs!=0 => return Ok(1),
+                    _ => {}
+                }
+                let rhs=rhs.eval(symbols)?;
+                if matches!(op, BinaryOp::Div | BinaryOp::Mod) && rhs==0 {
+                    return Err(EvalError::DivisionByZero(self.span));
+                }
+                // 和 RV32 的 add/sub/mul/div/rem 算出来的一样: 溢出时回绕,
+                // INT_MIN / -1 得到 INT_MIN, INT_MIN % -1 得到 0
+                Ok(match op {
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                    BinaryOp::Div => lhs.wrapping_div(rhs),
+                    BinaryOp::Mod => lhs.wrapping_rem(rhs),
+                    BinaryOp::Lt => (lhs<rhs) as i32,
+                    BinaryOp::Gt => (lhs>rhs) as i32,
+                    BinaryOp::Le => (lhs<=rhs) as i32,
+                    BinaryOp::Ge => (lhs>=rhs) as i32,
+                    BinaryOp::Eq => (lhs==rhs) as i32,
+                    BinaryOp::Ne => (lhs!=rhs) as i32,
+                    BinaryOp::And => (lhs != 0 && rhs != 0) as i32,
+                    BinaryOp::Or => (lhs != 0 || rhs != 0) as i32,
+                })
+            }
+            ExprKind::Var(ident) => element(ident, &[], symbols),
+            ExprKind::Index(ident, indices) => element(ident, indices, symbols),
+            // 常量表达式里不允许出现函数调用
+            ExprKind::Str(_) | ExprKind::Call(..) => Err(EvalError::NotConstant),
         }
     }
 }
 
-impl Evaluate for MulExp {
-    fn eval(&self) -> Option<i32> {
-        match self {
-            Self::UExp(unaryexp) => unaryexp.eval(),
-            Self::MExp(mulexp, mulop, unaryexp) => match (mulexp.eval(), unaryexp.eval()) {
-                (Some(lhs), Some(rhs)) => match mulop {
-                    MulOp::Div => Some(lhs/rhs),
-                    MulOp::Mod => Some(lhs%rhs),
-                    MulOp::Mul => Some(lhs*rhs),
-                },
-                _ => None,
-            }
+/// 常量的值, 或者用常量下标取到的常量数组元素的值
+fn element<T: Constant>(ident: &str, indices: &[Expr], symbols: &SymbolTable<T>) -> EvalResult {
+    let (dims, values)=symbols.lookup(ident).and_then(|symbol| symbol.constant()).ok_or(EvalError::NotConstant)?;
+    // 没有取到元素的数组不是 int, sema 会另外报错
+    if indices.len()!=dims.len() {
+        return Err(EvalError::NotConstant);
+    }
+    let mut offset=0;
+    for (index, &dim) in indices.iter().zip(dims) {
+        match index.eval(symbols)? {
+            i if i>=0 && (i as usize)<dim => offset=offset*dim+i as usize,
+            _ => return Err(EvalError::IndexOutOfBounds(index.span)),
         }
     }
+    Ok(values[offset])
 }
 
-impl Evaluate for AddExp {
-    fn eval(&self) -> Option<i32> {
-        match self {
-            Self::MExp(mulexp) => mulexp.eval(),
-            Self::AExp(addexp, addop, mulexp) => match (addexp.eval(), mulexp.eval()) {
-                (Some(lhs), Some(rhs)) => match addop {
-                    AddOp::Add => Some(lhs+rhs),
-                    AddOp::Sub => Some(lhs-rhs),
-                },
-                _ => None,
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 测试用的符号, 只有常量数组
+    struct Array(Vec<usize>, Vec<i32>);
+
+    impl Constant for Array {
+        fn constant(&self) -> Option<(&[usize], &[i32])> {
+            Some((&self.0, &self.1))
         }
     }
-}
 
-impl Evaluate for RelExp {
-    fn eval(&self) -> Option<i32> {
-        match self {
-            Self::AExp(addexp) => addexp.eval(),
-            Self::RExp(relexp, relop, addexp) => match (relexp.eval(), addexp.eval()) {
-                (Some(lhs), Some(rhs)) => match relop {
-                    RelOp::Ge => Some((lhs>=rhs) as i32),
-                    RelOp::Gt => Some((lhs>rhs) as i32),
-                    RelOp::Le => Some((lhs<=rhs) as i32),
-                    RelOp::Lt => Some((lhs<rhs) as i32),
-                },
-                _ => None,
-            }
-        }
+    fn lit(value: i32) -> Expr {
+        Expr::new(ExprKind::Lit(value), Span::default())
     }
-}
 
-impl Evaluate for EqExp {
-    fn eval(&self) -> Option<i32> {
-        match self {
-            Self::RExp(relexp) => relexp.eval(),
-            Self::EExp(eqexp, eqop, relexp) => match (eqexp.eval(), relexp.eval()) {
-                (Some(lhs), Some(rhs)) => match eqop {
-                    EqOp::Equ => Some((lhs==rhs) as i32),
-                    EqOp::Ne => Some((lhs!=rhs) as i32),
-                },
-                _ => None,
-            }
-        }
+    fn eval(op: BinaryOp, lhs: Expr, rhs: Expr) -> EvalResult {
+        let mut symbols=SymbolTable::new();
+        symbols.insert("c", Array(vec![2], vec![1, 2]));
+        Expr::binary(op, lhs, rhs).eval(&symbols)
     }
-}
 
-impl Evaluate for LAndExp {
-    fn eval(&self) -> Option<i32> {
-        match self {
-            Self::EExp(eqexp) => eqexp.eval(),
-            Self::LAExp(landexp, eqexp) => match (landexp.eval(), eqexp.eval()) {
-                (Some(lhs), Some(rhs)) => Some((lhs != 0 && rhs != 0) as i32),
-                _ => None,
-            }
-        }
+    #[test]
+    fn wrapping_arithmetic() {
+        assert_eq!(eval(BinaryOp::Add, lit(i32::MAX), lit(1)).unwrap(), i32::MIN);
+        assert_eq!(eval(BinaryOp::Sub, lit(i32::MIN), lit(1)).unwrap(), i32::MAX);
+        assert_eq!(eval(BinaryOp::Mul, lit(65536), lit(65536)).unwrap(), 0);
+        let neg=Expr::new(ExprKind::Unary(UnaryOp::Neg, Box::new(lit(i32::MIN))), Span::default());
+        assert_eq!(neg.eval(&SymbolTable::<Array>::new()).unwrap(), i32::MIN);
     }
-}
 
-impl Evaluate for LOrExp {
-    fn eval(&self) -> Option<i32> {
-        match self {
-            Self::LAExp(landexp) => landexp.eval(),
-            Self::LOExp(lorexp, landexp) => match (lorexp.eval(), landexp.eval()) {
-                (Some(lhs), Some(rhs)) => Some((lhs != 0 || rhs != 0) as i32),
-                _ => None,
-            }
-        }
+    #[test]
+    fn int_min_div_minus_one() {
+        assert_eq!(eval(BinaryOp::Div, lit(i32::MIN), lit(-1)).unwrap(), i32::MIN);
+        assert_eq!(eval(BinaryOp::Mod, lit(i32::MIN), lit(-1)).unwrap(), 0);
+        assert!(matches!(eval(BinaryOp::Div, lit(1), lit(0)), Err(EvalError::DivisionByZero(_))));
     }
-}
 
-impl Evaluate for ConstExp {
-    fn eval(&self) -> Option<i32> {
-        self.exp.eval()
+    #[test]
+    fn short_circuit() {
+        let div_zero=|| Expr::binary(BinaryOp::Div, lit(1), lit(0));
+        let out_of_bounds=|| Expr::new(ExprKind::Index("c".to_string(), vec![lit(5)]), Span::default());
+        assert_eq!(eval(BinaryOp::And, lit(0), div_zero()).unwrap(), 0);
+        assert_eq!(eval(BinaryOp::Or, lit(2), out_of_bounds()).unwrap(), 1);
+        assert!(matches!(eval(BinaryOp::And, lit(1), div_zero()), Err(EvalError::DivisionByZero(_))));
+        assert!(matches!(eval(BinaryOp::Or, lit(0), out_of_bounds()), Err(EvalError::IndexOutOfBounds(_))));
     }
-}
\ No newline at end of file
+}
@@ -1,342 +1,653 @@
 use crate::ast::*;
-use crate::eval::Evaluate;
-use std::sync::Mutex;
-use std::fmt;
+use crate::library::library;
+use crate::sema::InitList;
+use crate::symtab::SymbolTable;
+use crate::visit::{Visitor, walk_comp_unit};
+use koopa::ir::builder::{BlockBuilder, LocalBuilder};
+use koopa::ir::builder_traits::*;
+use koopa::ir::{BasicBlock, Function, FunctionData, Program, Type, TypeKind, Value, ValueKind};
 use std::collections::HashMap;
+use std::convert::Infallible;
 
-//static COUNT: AtomicUsize=AtomicUsize::new(0);
-
-use lazy_static::lazy_static;
-
-lazy_static! {
-    static ref COUNT: Mutex<u32> = Mutex::new(0);
-    static ref CONST_MAP: Mutex<HashMap<String, i32>> = Mutex::new(HashMap::new());
+/// IR 生成时名字对应的东西, 记录它在 IR 里对应的值
+#[derive(Debug, Clone)]
+enum Symbol {
+    /// 常量, 直接记下它的值
+    Const(i32),
+    /// 变量, 是 alloc 出来的指针
+    Var(Value),
+    /// 数组, 记录每一维的长度
+    Array { var: Value, dims: Vec<usize> },
+    /// 数组参数, 内存里存的是指向第一个元素的指针, 记录后面每一维的长度
+    Ptr { var: Value, dims: Vec<usize> },
 }
 
-pub struct Retpair{
-    pub irstr: String,
-    pub varstr: String,
+/// 一次 IR 生成过程中的全部状态, 每次编译都新建一个
+#[derive(Default)]
+pub struct IrGenContext {
+    program: Program,
+    // 所有函数, 包括运行时库里的, 调用时按名字查
+    funcs: HashMap<String, Function>,
+    // 正在生成的函数和基本块
+    func: Option<Function>,
+    block: Option<BasicBlock>,
+    // 给局部变量和基本块编号, 保证它们在 IR 里不重名
+    name_count: u32,
+    // 当前函数里所有的 alloc, 最后统一放在 %entry 开头
+    allocs: Vec<Value>,
+    // 当前基本块是否已经以 ret/jump/br 结尾
+    terminated: bool,
+    // 当前生成的代码执行不到, 生成的指令和基本块都不放进函数里
+    dead: bool,
+    // 外层循环的 (continue 目标, break 目标), 最内层的在最后
+    loops: Vec<(BasicBlock, BasicBlock)>,
+    symbols: SymbolTable<Symbol>,
+    // 已经生成的字符串字面量, 后端要把它们和用户定义的全局变量区分开
+    strings: Vec<Value>,
 }
 
-impl fmt::Display for Retpair {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f,"{}",self.irstr)
+impl IrGenContext {
+    /// 声明 SysY 运行时库里的函数
+    fn declare_library(&mut self) {
+        for func in library() {
+            let params=func.params.iter().map(ir_type).collect();
+            let decl=FunctionData::new_decl(format!("@{}",func.name), params, ir_type(&func.ret));
+            let decl=self.program.new_func(decl);
+            self.funcs.insert(func.name.to_string(), decl);
+        }
     }
-}
 
-pub trait IR {
-    fn irdump(&self) -> Retpair;
-} 
+    /// 把字符串字面量 (引号里原样的内容) 放到一个全局变量里, 返回这个全局变量.
+    /// Koopa 里没有 i8, 处理完转义后按小端序每 4 个字节拼成一个 i32, 末尾补 0
+    fn new_string(&mut self, literal: &str) -> Value {
+        let mut bytes=Vec::new();
+        let mut chars=literal.chars();
+        while let Some(c)=chars.next() {
+            let c=match c {
+                '\\' => match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('0') => '\0',
+                    Some(c) => c,
+                    None => '\\',
+                },
+                c => c,
+            };
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+        bytes.resize(bytes.len()/4*4+4, 0);
+        let words=bytes.chunks(4).map(|word| {
+            let word=i32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            self.program.new_value().integer(word)
+        }).collect();
+        let init=self.program.new_value().aggregate(words);
+        let string=self.program.new_value().global_alloc(init);
+        self.program.set_value_name(string, Some(format!("@__str_{}",self.strings.len())));
+        self.strings.push(string);
+        string
+    }
 
-impl IR for CompUnit {
-    fn irdump(&self) -> Retpair {
-        self.func_def.irdump()
+    /// 在当前函数里新建一个值
+    fn new_value(&mut self) -> LocalBuilder<'_> {
+        self.program.func_mut(self.func.unwrap()).dfg_mut().new_value()
     }
-}
 
-impl IR for Decl {
-    fn irdump(&self) -> Retpair {
-        match self {
-            Self::CDecl(constdecl) => constdecl.irdump(),
-            _ => unimplemented!(),
-        }
+    /// 在当前函数里新建一个基本块
+    fn new_bb(&mut self) -> BlockBuilder<'_> {
+        self.program.func_mut(self.func.unwrap()).dfg_mut().new_bb()
+    }
+
+    /// 当前函数里的整数常量
+    fn integer(&mut self, value: i32) -> Value {
+        self.new_value().integer(value)
     }
-}
 
-impl IR for ConstDecl {
-    fn irdump(&self) -> Retpair {
-        for i in self.constdefs.iter() {
-            i.irdump();
+    /// value 是不是常量 0
+    fn is_zero(&self, value: Value) -> bool {
+        let dfg=self.program.func(self.func.unwrap()).dfg();
+        matches!(dfg.value(value).kind(), ValueKind::Integer(int) if int.value()==0)
+    }
+
+    /// 把指令放到当前基本块的末尾, 返回这条指令
+    fn push(&mut self, inst: Value) -> Value {
+        if !self.dead {
+            let layout=self.program.func_mut(self.func.unwrap()).layout_mut();
+            layout.bb_mut(self.block.unwrap()).insts_mut().push_key_back(inst).unwrap();
         }
-        Retpair { irstr: format!(""), varstr: format!("") }
+        inst
     }
-}
 
-// impl IR for BType {
-//     fn irdump(&self) -> Retpair {
-//         match self {
-//             Self::Int => Retpair { irstr: format!(""), varstr: format!("int") }
-//         }
-//     }
-// }
+    /// 为局部的 ident 申请一个 IR 中唯一的名字,
+    /// 用 % 开头以免和 @ 开头的全局变量重名
+    fn new_name(&mut self, ident: &str) -> String {
+        let name=format!("%{}_{}",ident,self.name_count);
+        self.name_count+=1;
+        name
+    }
 
-impl IR for ConstDef {
-    fn irdump(&self) -> Retpair {
-        CONST_MAP.lock().unwrap().insert(self.ident.clone(), self.constinitval.constexp.eval().unwrap());
-        Retpair { irstr: format!(""), varstr: format!("") }
+    /// 为变量 ident 申请一个名字, 并在 %entry 里为它 alloc
+    fn new_var(&mut self, ident: &str) -> Value {
+        self.new_alloc(ident, Type::get_i32())
     }
-}
 
-impl IR for FuncDef {
-    fn irdump(&self) -> Retpair {
-        *COUNT.lock().unwrap()=0;
-        Retpair{
-            irstr: format!("fun @{}(): {} {{\n{}}}\n",self.ident,self.func_type.irdump(),self.block.irdump()),
-            varstr: self.ident.clone(),
-        } 
+    /// 为类型是 ty 的变量 ident 申请一个名字, 并在 %entry 里为它 alloc
+    fn new_alloc(&mut self, ident: &str, ty: Type) -> Value {
+        let name=self.new_name(ident);
+        let var=self.new_value().alloc(ty);
+        self.program.func_mut(self.func.unwrap()).dfg_mut().set_value_name(var, Some(name));
+        self.allocs.push(var);
+        var
     }
-}
 
-impl IR for FuncType {
-    fn irdump(&self) -> Retpair {
-        match self {
-            FuncType::Int => Retpair{
-                irstr: String::from("i32"),
-                varstr: String::from("")
-            }
+    /// 申请一个新的基本块
+    fn new_label(&mut self, name: &str) -> BasicBlock {
+        let name=self.new_name(name);
+        self.new_bb().basic_block(Some(name))
+    }
+
+    /// 开始一个新的基本块
+    fn start_block(&mut self, bb: BasicBlock) {
+        self.terminated=false;
+        self.block=Some(bb);
+        if !self.dead {
+            self.program.func_mut(self.func.unwrap()).layout_mut().bbs_mut().push_key_back(bb).unwrap();
         }
     }
+
+    /// 跳转到 bb, 当前基本块已经结束时什么也不生成
+    fn jump(&mut self, bb: BasicBlock) {
+        if !self.terminated {
+            self.terminated=true;
+            let jump=self.new_value().jump(bb);
+            self.push(jump);
+        }
+    }
+
+    /// 查找名字, sema 已经检查过所有名字都有定义
+    fn lookup(&self, ident: &str) -> Symbol {
+        self.symbols.lookup(ident).cloned().expect("sema should have resolved every name")
+    }
 }
 
-impl IR for Block {
-    fn irdump(&self) -> Retpair {
-        let its=self.items.iter().map(|x| x.irdump().irstr).collect::<Vec<_>>().join("");
+/// 表达式生成代码, 返回它的值
+pub trait IR {
+    fn irdump(&self, ctx: &mut IrGenContext) -> Value;
+}
 
-        Retpair{
-            irstr: format!("%entry: \n{}\n",its),
-            varstr: format!("%entry")
-        }
+impl IrGenContext {
+    /// 生成整个程序的 Koopa IR, comp_unit 必须已经通过了 sema 的检查.
+    /// 同时返回字符串字面量对应的全局变量, 它们的名字可能和用户的全局变量冲突
+    pub fn generate(mut self, comp_unit: &CompUnit) -> (Program, Vec<Value>) {
+        let Ok(())=self.visit_comp_unit(comp_unit);
+        (self.program, self.strings)
     }
 }
 
-impl IR for BlockItem {
-    fn irdump(&self) -> Retpair {
-        match self {
-            Self::Decl(decl) => decl.irdump(),
-            Self::Stmt(stmt) => stmt.irdump(),
+// 声明和语句直接把指令放进当前基本块, 表达式则通过 IR 返回它的值.
+// 语义错误都已经由 sema 报告过了, 这里不会出错
+impl Visitor for IrGenContext {
+    type Error = Infallible;
+
+    fn visit_comp_unit(&mut self, comp_unit: &CompUnit) -> Result<(), Infallible> {
+        self.declare_library();
+        walk_comp_unit(self, comp_unit)
+    }
+
+    fn visit_const_def(&mut self, def: &ConstDef) -> Result<(), Infallible> {
+        let values=literals(def.constinitval.exps());
+        // 常量直接记下它的值, 常量数组可能用变量下标访问, 还是要放到内存里
+        match &def.ty {
+            Ty::Array(dims) => define(&def.ident, dims.clone(), Some(Init::Const(values)), self),
+            _ => {
+                self.symbols.insert(&def.ident, Symbol::Const(values[0]));
+            }
         }
+        Ok(())
     }
-}
 
-impl IR for Stmt {
-    fn irdump(&self) -> Retpair {
-        match self {
-            Self::Assign(lval, exp) => unimplemented!(),
-            Self::Return(exp) => unimplemented!(),
+    fn visit_var_def(&mut self, def: &VarDef) -> Result<(), Infallible> {
+        let dims=match &def.ty {
+            Ty::Array(dims) => dims.clone(),
+            _ => Vec::new(),
+        };
+        let init=def.initval.as_ref().map(|initval| match self.symbols.is_global() {
+            true => Init::Const(literals(initval.exps())),
+            false => Init::Values(initval.exps().iter().map(|exp| exp.irdump(self)).collect()),
+        });
+        define(&def.ident, dims, init, self);
+        Ok(())
+    }
+
+    fn visit_func_def(&mut self, func_def: &FuncDef) -> Result<(), Infallible> {
+        let void=matches!(func_def.func_type, FuncType::Void);
+        let param_types: Vec<Type>=func_def.params.iter().map(|param| ir_type(&param.ty)).collect();
+        let params=func_def.params.iter().zip(&param_types).map(|(param, ty)| (Some(self.new_name(&param.ident)), ty.clone())).collect();
+        let ret=if void { Type::get_unit() } else { Type::get_i32() };
+        // 先定义函数名, 函数体里才能递归调用
+        let func=self.program.new_func(FunctionData::with_param_names(format!("@{}",func_def.ident), params, ret));
+        self.funcs.insert(func_def.ident.clone(), func);
+        self.func=Some(func);
+        self.allocs.clear();
+        self.dead=false;
+        let entry=self.new_bb().basic_block(Some(String::from("%entry")));
+        self.start_block(entry);
+        // 参数和函数体最外层的声明在同一个作用域里
+        self.symbols.push_scope();
+        let args=self.program.func(func).params().to_vec();
+        for ((param, ty), arg) in func_def.params.iter().zip(param_types).zip(args) {
+            let var=self.new_alloc(&param.ident, ty);
+            let symbol=match &param.ty {
+                Ty::Ptr(dims) => Symbol::Ptr { var, dims: dims.clone() },
+                _ => Symbol::Var(var),
+            };
+            self.symbols.insert(&param.ident, symbol);
+            let store=self.new_value().store(arg, var);
+            self.push(store);
         }
-        // let ret=self.exp.irdump();
-        // Retpair {
-        //     irstr: format!("{}  ret {}",ret,ret.varstr),
-        //     varstr: format!("")
-        // }
+        block_items(&func_def.block, self);
+        self.symbols.pop_scope();
+        // 最后一个基本块也必须有结尾, void 函数隐式返回,
+        // 非 void 函数走到这里的返回值是未定义的, 就返回 0
+        if !self.terminated {
+            let value=if void { None } else { Some(self.integer(0)) };
+            let ret=self.new_value().ret(value);
+            self.push(ret);
+        }
+        // 所有 alloc 放在 %entry 开头
+        let insts=self.program.func_mut(func).layout_mut().bb_mut(entry).insts_mut();
+        for &alloc in self.allocs.iter().rev() {
+            insts.push_key_front(alloc).unwrap();
+        }
+        Ok(())
     }
-}
 
-impl IR for Exp {
-    fn irdump(&self) -> Retpair {
-        self.lorexp.irdump()
+    fn visit_block(&mut self, block: &Block) -> Result<(), Infallible> {
+        self.symbols.push_scope();
+        block_items(block, self);
+        self.symbols.pop_scope();
+        Ok(())
     }
-}
 
-impl IR for PrimaryExp {
-    fn irdump(&self) -> Retpair {
-        match self {
-            PrimaryExp::Exp(exp) => exp.irdump(),
-            PrimaryExp::Number(num) => Retpair {
-                irstr: format!(""),
-                varstr: format!("{num}")
-            },
-            PrimaryExp::LVal(lval) => {
-                if let Some(ident) = CONST_MAP.lock().unwrap().get(&lval.ident) {
-                    Retpair {
-                        irstr: format!(""),
-                        varstr: format!("{ident}")
-                    }
+    fn visit_stmt(&mut self, stmt: &Stmt) -> Result<(), Infallible> {
+        match stmt {
+            Stmt::Assign(lval, exp, _) => {
+                let (ptr, _)=lval_ptr(&lval.ident, &lval.indices, self);
+                let value=exp.irdump(self);
+                let store=self.new_value().store(value, ptr);
+                self.push(store);
+            }
+            Stmt::Exp(exp, _) => if let Some(exp)=exp {
+                exp.irdump(self);
+            }
+            Stmt::Return(exp, _) => {
+                let value=exp.as_ref().map(|exp| exp.irdump(self));
+                let ret=self.new_value().ret(value);
+                self.push(ret);
+                self.terminated=true;
+            }
+            Stmt::Block(block) => self.visit_block(block)?,
+            Stmt::If(cond, then, els, _) => {
+                let cond=cond.irdump(self);
+                let then_bb=self.new_label("then");
+                let else_bb=self.new_label("else");
+                let end_bb=self.new_label("if_end");
+                let false_bb=if els.is_some() { else_bb } else { end_bb };
+                let br=self.new_value().branch(cond, then_bb, false_bb);
+                self.push(br);
+                self.start_block(then_bb);
+                self.visit_stmt(then)?;
+                // 两个分支都以 ret/break/continue 结尾时 if 之后执行不到
+                let mut end_reachable=!self.terminated;
+                self.jump(end_bb);
+                if let Some(els)=els {
+                    self.start_block(else_bb);
+                    self.visit_stmt(els)?;
+                    end_reachable|=!self.terminated;
+                    self.jump(end_bb);
                 }
                 else {
-                    Retpair {
-                        irstr: format!(""),
-                        varstr: format!("{}",lval.ident)
-                    }
+                    end_reachable=true;
+                }
+                if end_reachable {
+                    self.start_block(end_bb);
                 }
             }
+            Stmt::While(cond, body, _) => {
+                let entry_bb=self.new_label("while_entry");
+                let body_bb=self.new_label("while_body");
+                let end_bb=self.new_label("while_end");
+                self.jump(entry_bb);
+                self.start_block(entry_bb);
+                let cond=cond.irdump(self);
+                let br=self.new_value().branch(cond, body_bb, end_bb);
+                self.push(br);
+                self.start_block(body_bb);
+                self.loops.push((entry_bb, end_bb));
+                self.visit_stmt(body)?;
+                self.loops.pop();
+                self.jump(entry_bb);
+                self.start_block(end_bb);
+            }
+            // sema 已经检查过 break 和 continue 都在循环里
+            Stmt::Break(_) => {
+                let (_, end_bb)=*self.loops.last().unwrap();
+                self.jump(end_bb);
+            }
+            Stmt::Continue(_) => {
+                let (entry_bb, _)=*self.loops.last().unwrap();
+                self.jump(entry_bb);
+            }
         }
+        Ok(())
     }
 }
 
-impl IR for UnaryExp {
-    fn irdump(&self) -> Retpair {
-        match self {
-            UnaryExp::PExp(primaryexp) => primaryexp.irdump(),
-            UnaryExp::UExp(op, unaryexp) => {
-                let ret=unaryexp.irdump();
-                let count=*COUNT.lock().unwrap();
-                *COUNT.lock().unwrap()+=1;
-                Retpair {
-                    irstr: format!("{}  %{} = {} {}\n",ret,count,op.irdump(),ret.varstr),
-                    varstr: format!("%{}",count)
-                }
-            }
-        }
-    }
+/// 数组的 Koopa 类型, 如 int a[2][3] 对应 [[i32, 3], 2]
+fn array_type(dims: &[usize]) -> Type {
+    dims.iter().rev().fold(Type::get_i32(), |ty, &dim| Type::get_array(ty, dim))
 }
 
-impl IR for UnaryOp {
-    fn irdump(&self) -> Retpair {
-        match self {
-            UnaryOp::Neg => Retpair {
-                irstr: String::from("sub 0,"),
-                varstr: format!("")
-            },
-            UnaryOp::Not => Retpair {
-                irstr: String::from("eq 0,"),
-                varstr: format!("")
-            },
-            UnaryOp::Inv => Retpair {
-                irstr: String::from("xor -1,"),
-                varstr: format!("")
-            },
-            UnaryOp::Pos => Retpair {
-                irstr: String::from("add 0,"),
-                varstr: format!("")
-            },
-        }
+/// 参数和返回值的 Koopa 类型. 数组参数实际传进来的是指向第一个元素的指针,
+/// 字符串是按 int 存放的, 传的也是指向第一个元素的指针
+fn ir_type(ty: &Ty) -> Type {
+    match ty {
+        Ty::Int => Type::get_i32(),
+        Ty::Void => Type::get_unit(),
+        Ty::Str => Type::get_pointer(Type::get_i32()),
+        Ty::Ptr(dims) => Type::get_pointer(array_type(dims)),
+        Ty::Array(dims) => array_type(dims),
     }
 }
 
-impl IR for MulExp {
-    fn irdump(&self) -> Retpair {
-        match self {
-            MulExp::UExp(unaryexp) => unaryexp.irdump(),
-            MulExp::MExp(mulexp, op, unaryexp) => {
-                let retleft=mulexp.irdump();
-                let retright=unaryexp.irdump();
-                let count=*COUNT.lock().unwrap();
-                *COUNT.lock().unwrap()+=1;
-                Retpair {
-                    irstr: format!("{}{}  %{} = {} {}, {}\n",retleft,retright,count,op.irdump(),retleft.varstr,retright.varstr),
-                    varstr: format!("%{}",count)
-                }
-            }
-        }
-    }
+/// 展开后每个元素的初始值: 编译期算好的常量, 或者局部变量在运行时算出来的值
+enum Init {
+    Const(Vec<i32>),
+    Values(Vec<Value>),
 }
 
-impl IR for MulOp {
-    fn irdump(&self) -> Retpair {
-        match self {
-            MulOp::Div => Retpair { irstr: String::from("div"), varstr: String::from("") },
-            MulOp::Mod => Retpair { irstr: String::from("mod"), varstr: String::from("") },
-            MulOp::Mul => Retpair { irstr: String::from("mul"), varstr: String::from("") }
-        }
+/// 常量和全局变量的初始值已经被 sema 算成了字面量
+fn literals(exps: Vec<&Expr>) -> Vec<i32> {
+    exps.iter().map(|exp| match exp.kind {
+        ExprKind::Lit(value) => value,
+        _ => unreachable!("sema folds constant initializers"),
+    }).collect()
+}
+
+/// 把展开后的初始值按 dims 拼成全局变量的初始化列表
+fn aggregate(values: &[i32], dims: &[usize], program: &mut Program) -> Value {
+    if dims.is_empty() {
+        return program.new_value().integer(values[0]);
     }
+    let chunk=values.len()/dims[0];
+    let elems=values.chunks(chunk).map(|values| aggregate(values, &dims[1..], program)).collect();
+    program.new_value().aggregate(elems)
 }
 
-impl IR for AddExp {
-    fn irdump(&self) -> Retpair {
-        match self {
-            AddExp::MExp(mulexp) => mulexp.irdump(),
-            AddExp::AExp(addexp, op, mulexp) => {
-                let retleft=addexp.irdump();
-                let retright=mulexp.irdump();
-                let count=*COUNT.lock().unwrap();
-                *COUNT.lock().unwrap()+=1;
-                Retpair {
-                    irstr: format!("{}{}  %{} = {} {}, {}\n",retleft,retright,count,op.irdump(),retleft.varstr,retright.varstr),
-                    varstr: format!("%{}",count)
+/// 定义变量或者数组 ident, init 是展开后每个元素的初始值.
+/// 全局的直接带上初始值, 局部数组先用循环清零, 再 store 不是 0 的元素
+fn define(ident: &str, dims: Vec<usize>, init: Option<Init>, ctx: &mut IrGenContext) {
+    let ty=array_type(&dims);
+    let var=if ctx.symbols.is_global() {
+        // 没有初始值时为 0
+        let init=match init {
+            Some(Init::Const(values)) => aggregate(&values, &dims, &mut ctx.program),
+            _ => ctx.program.new_value().zero_init(ty),
+        };
+        let var=ctx.program.new_value().global_alloc(init);
+        ctx.program.set_value_name(var, Some(format!("@{}",ident)));
+        var
+    }
+    else {
+        let var=ctx.new_alloc(ident, ty);
+        let values: Vec<Value>=match init {
+            Some(Init::Const(values)) => values.into_iter().map(|value| ctx.integer(value)).collect(),
+            Some(Init::Values(values)) => values,
+            None => Vec::new(),
+        };
+        if dims.is_empty() {
+            if let Some(&value)=values.first() {
+                let store=ctx.new_value().store(value, var);
+                ctx.push(store);
+            }
+        }
+        else if !values.is_empty() {
+            // 按一维数组访问: 取到指向第一个元素的指针, 第 i 个元素用 getptr base, i
+            let mut base=var;
+            for _ in &dims {
+                let zero=ctx.integer(0);
+                let gep=ctx.new_value().get_elem_ptr(base, zero);
+                base=ctx.push(gep);
+            }
+            // sema 补上的元素都是 0, 有 0 就先整个清零, 不用每个元素 store 一次
+            if values.iter().any(|&value| ctx.is_zero(value)) {
+                zero_fill(base, values.len(), ctx);
+            }
+            for (i, value) in values.into_iter().enumerate() {
+                if ctx.is_zero(value) {
+                    continue;
                 }
+                let index=ctx.integer(i as i32);
+                let getptr=ctx.new_value().get_ptr(base, index);
+                let ptr=ctx.push(getptr);
+                let store=ctx.new_value().store(value, ptr);
+                ctx.push(store);
             }
         }
-    }
+        var
+    };
+    let symbol=if dims.is_empty() { Symbol::Var(var) } else { Symbol::Array { var, dims } };
+    ctx.symbols.insert(ident, symbol);
 }
 
-impl IR for AddOp {
-    fn irdump(&self) -> Retpair {
-        match self {
-            AddOp::Add => Retpair { irstr: String::from("add"), varstr: String::from("") },
-            AddOp::Sub => Retpair { irstr: String::from("sub"), varstr: String::from("") },
+/// 用一个循环把从 base 开始的 len 个 int 清零
+fn zero_fill(base: Value, len: usize, ctx: &mut IrGenContext) {
+    let i=ctx.new_var("zero_i");
+    let zero=ctx.integer(0);
+    let store=ctx.new_value().store(zero, i);
+    ctx.push(store);
+    let entry_bb=ctx.new_label("zero_entry");
+    let body_bb=ctx.new_label("zero_body");
+    let end_bb=ctx.new_label("zero_end");
+    ctx.jump(entry_bb);
+    ctx.start_block(entry_bb);
+    let load=ctx.new_value().load(i);
+    let index=ctx.push(load);
+    let len=ctx.integer(len as i32);
+    let lt=ctx.new_value().binary(koopa::ir::BinaryOp::Lt, index, len);
+    let cond=ctx.push(lt);
+    let br=ctx.new_value().branch(cond, body_bb, end_bb);
+    ctx.push(br);
+    ctx.start_block(body_bb);
+    let getptr=ctx.new_value().get_ptr(base, index);
+    let ptr=ctx.push(getptr);
+    let store=ctx.new_value().store(zero, ptr);
+    ctx.push(store);
+    let one=ctx.integer(1);
+    let add=ctx.new_value().binary(koopa::ir::BinaryOp::Add, index, one);
+    let next=ctx.push(add);
+    let store=ctx.new_value().store(next, i);
+    ctx.push(store);
+    ctx.jump(entry_bb);
+    ctx.start_block(end_bb);
+}
+
+/// 生成块里的每一项, 不新建作用域
+fn block_items(block: &Block, ctx: &mut IrGenContext) {
+    for item in block.items.iter() {
+        // 基本块已经结束时后面的代码执行不到, 生成的指令和基本块都不放进函数里,
+        // 否则 ret/jump 之后还会跟着指令
+        let dead=ctx.dead;
+        ctx.dead|=ctx.terminated;
+        let Ok(())=ctx.visit_block_item(item);
+        if ctx.dead {
+            ctx.terminated=true;
         }
+        ctx.dead=dead;
     }
 }
 
-impl IR for RelExp {
-    fn irdump(&self) -> Retpair {
-        match self {
-            RelExp::AExp(addexp) => addexp.irdump(),
-            RelExp::RExp(relexp, op, addexp) => {
-                let retleft=relexp.irdump();
-                let retright=addexp.irdump();
-                let count=*COUNT.lock().unwrap();
-                *COUNT.lock().unwrap()+=1;
-                Retpair {
-                    irstr: format!("{}{}  %{} = {} {}, {}\n",retleft,retright,count,op.irdump(),retleft.varstr,retright.varstr),
-                    varstr: format!("%{}",count)
-                }
+impl IR for Expr {
+    fn irdump(&self, ctx: &mut IrGenContext) -> Value {
+        match &self.kind {
+            ExprKind::Lit(value) => ctx.integer(*value),
+            // 字符串只能是 putf 的格式串, 放到全局变量里, 传它第一个元素的地址
+            ExprKind::Str(literal) => {
+                let string=ctx.new_string(literal);
+                let zero=ctx.integer(0);
+                let gep=ctx.new_value().get_elem_ptr(string, zero);
+                ctx.push(gep)
+            }
+            ExprKind::Var(ident) => lval_value(ident, &[], ctx),
+            ExprKind::Index(ident, indices) => lval_value(ident, indices, ctx),
+            ExprKind::Call(ident, args) => call(ident, args, ctx),
+            ExprKind::Unary(op, exp) => {
+                let value=exp.irdump(ctx);
+                let (op, lhs)=op.ir_op();
+                let lhs=ctx.integer(lhs);
+                let inst=ctx.new_value().binary(op, lhs, value);
+                ctx.push(inst)
+            }
+            // 短路求值: 左边为 0 时结果就是 0, 不再计算右边
+            ExprKind::Binary(BinaryOp::And, lhs, rhs) => short_circuit(lhs, rhs, "land", 0, ctx),
+            // 短路求值: 左边非 0 时结果就是 1, 不再计算右边
+            ExprKind::Binary(BinaryOp::Or, lhs, rhs) => short_circuit(lhs, rhs, "lor", 1, ctx),
+            ExprKind::Binary(op, lhs, rhs) => {
+                let lhs=lhs.irdump(ctx);
+                let rhs=rhs.irdump(ctx);
+                let inst=ctx.new_value().binary(op.ir_op(), lhs, rhs);
+                ctx.push(inst)
             }
         }
     }
 }
 
-impl IR for RelOp {
-    fn irdump(&self) -> Retpair {
-        match self {
-            RelOp::Lt => Retpair { irstr: String::from("lt"), varstr: String::from("") },
-            RelOp::Gt => Retpair { irstr: String::from("gt"), varstr: String::from("") },
-            RelOp::Le => Retpair { irstr: String::from("le"), varstr: String::from("") },
-            RelOp::Ge => Retpair { irstr: String::from("ge"), varstr: String::from("") },
-        }
+/// && 和 ||: 先把 value 存进结果, 左边的值等于 value 时直接跳到结尾,
+/// 否则结果是右边是否非 0
+fn short_circuit(lhs: &Expr, rhs: &Expr, name: &str, value: i32, ctx: &mut IrGenContext) -> Value {
+    let lhs=lhs.irdump(ctx);
+    let result=ctx.new_var(name);
+    let rhs_bb=ctx.new_label(&format!("{}_rhs",name));
+    let end_bb=ctx.new_label(&format!("{}_end",name));
+    let (true_bb, false_bb)=if value==0 { (rhs_bb, end_bb) } else { (end_bb, rhs_bb) };
+    let value=ctx.integer(value);
+    let store=ctx.new_value().store(value, result);
+    ctx.push(store);
+    let br=ctx.new_value().branch(lhs, true_bb, false_bb);
+    ctx.push(br);
+    ctx.start_block(rhs_bb);
+    let rhs=rhs.irdump(ctx);
+    let zero=ctx.integer(0);
+    let ne=ctx.new_value().binary(koopa::ir::BinaryOp::NotEq, zero, rhs);
+    let rhs=ctx.push(ne);
+    let store=ctx.new_value().store(rhs, result);
+    ctx.push(store);
+    let jump=ctx.new_value().jump(end_bb);
+    ctx.push(jump);
+    ctx.start_block(end_bb);
+    let load=ctx.new_value().load(result);
+    ctx.push(load)
+}
+
+/// 左值的值: 取到元素时 load 出它的值;
+/// 取到的还是数组时得到指向它第一个元素的指针
+fn lval_value(ident: &str, indices: &[Expr], ctx: &mut IrGenContext) -> Value {
+    if let Symbol::Const(value)=ctx.lookup(ident) {
+        return ctx.integer(value);
     }
+    let (ptr, element)=lval_ptr(ident, indices, ctx);
+    if !element {
+        return ptr;
+    }
+    let load=ctx.new_value().load(ptr);
+    ctx.push(load)
 }
 
-impl IR for EqExp {
-    fn irdump(&self) -> Retpair {
-        match self {
-            EqExp::RExp(relexp) => relexp.irdump(),
-            EqExp::EExp(eqexp, op, relexp) => {
-                let retleft=eqexp.irdump();
-                let retright=relexp.irdump();
-                let count=*COUNT.lock().unwrap();
-                *COUNT.lock().unwrap()+=1;
-                Retpair {
-                    irstr: format!("{}{}  %{} = {} {}, {}\n",retleft,retright,count,op.irdump(),retleft.varstr,retright.varstr),
-                    varstr: format!("%{}",count)
-                }
-            }
+/// 计算左值的地址, 以及取到的是不是单个元素.
+/// 取到的还是数组时, 和 lval_value 一样得到指向它第一个元素的指针
+fn lval_ptr(ident: &str, indices: &[Expr], ctx: &mut IrGenContext) -> (Value, bool) {
+    let mut indices=indices.iter();
+    let (mut ptr, mut dims)=match ctx.lookup(ident) {
+        Symbol::Var(var) => (var, Vec::new()),
+        Symbol::Array { var, dims } => (var, dims),
+        // 数组参数里存的是指针, 先 load 出来, 第一个下标用 getptr
+        Symbol::Ptr { var, dims } => {
+            let load=ctx.new_value().load(var);
+            let ptr=ctx.push(load);
+            let index=match indices.next() {
+                Some(index) => index.irdump(ctx),
+                None => return (ptr, false),
+            };
+            let getptr=ctx.new_value().get_ptr(ptr, index);
+            (ctx.push(getptr), dims)
         }
+        Symbol::Const(_) => unreachable!("constants have no address"),
+    };
+    // 每个下标取一次 getelemptr, 从数组的指针一层层取到元素的指针
+    for index in indices {
+        let index=index.irdump(ctx);
+        let gep=ctx.new_value().get_elem_ptr(ptr, index);
+        ptr=ctx.push(gep);
+        dims.remove(0);
+    }
+    if dims.is_empty() {
+        return (ptr, true);
     }
+    // 数组作为值时退化成指向第一个元素的指针
+    let zero=ctx.integer(0);
+    let gep=ctx.new_value().get_elem_ptr(ptr, zero);
+    (ctx.push(gep), false)
 }
 
-impl IR for EqOp {
-    fn irdump(&self) -> Retpair {
-        match self {
-            EqOp::Equ => Retpair { irstr: String::from("eq"), varstr: String::from("") },
-            EqOp::Ne => Retpair { irstr: String::from("ne"), varstr: String::from("") },
-        }
+/// 生成函数调用, void 函数调用的结果不会被用到
+fn call(ident: &str, args: &[Expr], ctx: &mut IrGenContext) -> Value {
+    let mut args: Vec<Value>=args.iter().map(|arg| arg.irdump(ctx)).collect();
+    let callee=ctx.funcs[ident];
+    // 可变参数的函数按全部参数声明, 没给的参数补 0
+    let params=match ctx.program.func(callee).ty().kind() {
+        TypeKind::Function(params, _) => params.len(),
+        _ => unreachable!(),
+    };
+    if args.len()<params {
+        let zero=ctx.integer(0);
+        args.resize(params, zero);
     }
+    let call=ctx.new_value().call(callee, args);
+    ctx.push(call)
 }
 
-impl IR for LAndExp {
-    fn irdump(&self) -> Retpair {
+impl UnaryOp {
+    /// 一元运算都写成左边是常量的 Koopa 二元运算, 返回 (运算, 左边的常量)
+    fn ir_op(&self) -> (koopa::ir::BinaryOp, i32) {
+        use koopa::ir::BinaryOp as IrOp;
         match self {
-            LAndExp::EExp(eqexp) => eqexp.irdump(),
-            LAndExp::LAExp(landexp, eqexp) => {
-                let retleft=landexp.irdump();
-                let retright=eqexp.irdump();
-                let count=*COUNT.lock().unwrap();
-                *COUNT.lock().unwrap()+=3;
-                Retpair {
-                    irstr: format!("{}{}  %{} = ne 0, {}\n  %{} = ne 0, {}\n  %{} = and %{}, %{}\n",retleft,retright,count,retleft.varstr,count+1,retright.varstr,count+2,count,count+1),
-                    varstr: format!("%{}",count+2)
-                }
-            }
+            UnaryOp::Neg => (IrOp::Sub, 0),
+            UnaryOp::Not => (IrOp::Eq, 0),
+            UnaryOp::Inv => (IrOp::Xor, -1),
+            UnaryOp::Pos => (IrOp::Add, 0),
         }
     }
 }
 
-impl IR for LOrExp {
-    fn irdump(&self) -> Retpair {
+impl BinaryOp {
+    /// 对应的 Koopa 二元运算.
+    /// && 和 || 要短路求值, 在 short_circuit 里单独生成, 用不到这里
+    fn ir_op(&self) -> koopa::ir::BinaryOp {
+        use koopa::ir::BinaryOp as IrOp;
         match self {
-            LOrExp::LAExp(landexp) => landexp.irdump(),
-            LOrExp::LOExp(lorexp, landexp) => {
-                let retleft=lorexp.irdump();
-                let retright=landexp.irdump();
-                let count=*COUNT.lock().unwrap();
-                *COUNT.lock().unwrap()+=3;
-                Retpair {
-                    irstr: format!("{}{}  %{} = ne 0, {}\n  %{} = ne 0, {}\n  %{} = or %{}, %{}\n",retleft,retright,count,retleft.varstr,count+1,retright.varstr,count+2,count,count+1),
-                    varstr: format!("%{}",count+2)
-                }
-            }
+            BinaryOp::Add => IrOp::Add,
+            BinaryOp::Sub => IrOp::Sub,
+            BinaryOp::Mul => IrOp::Mul,
+            BinaryOp::Div => IrOp::Div,
+            BinaryOp::Mod => IrOp::Mod,
+            BinaryOp::Lt => IrOp::Lt,
+            BinaryOp::Gt => IrOp::Gt,
+            BinaryOp::Le => IrOp::Le,
+            BinaryOp::Ge => IrOp::Ge,
+            BinaryOp::Eq => IrOp::Eq,
+            BinaryOp::Ne => IrOp::NotEq,
+            BinaryOp::And => IrOp::And,
+            BinaryOp::Or => IrOp::Or,
         }
     }
-}
\ No newline at end of file
+}
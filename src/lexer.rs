@@ -0,0 +1,292 @@
+use crate::ast::Span;
+use crate::diag::Diagnostic;
+use std::fmt;
+
+/// 词法分析得到的 token
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tok {
+    Ident(String),
+    IntConst(i32),
+    // 字符串字面量引号里原样的内容, 转义字符留到生成 IR 时再处理
+    StringConst(String),
+    Const, Int, Void, If, Else, While, Break, Continue, Return,
+    LParen, RParen, LBrace, RBrace, LBracket, RBracket, Semi, Comma, Assign,
+    Plus, Minus, Star, Slash, Percent, Not, Tilde,
+    Lt, Gt, Le, Ge, Eq, Ne, And, Or,
+}
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s=match self {
+            Tok::Ident(ident) => ident,
+            Tok::IntConst(value) => return write!(f,"{}",value),
+            Tok::StringConst(s) => return write!(f,"\"{}\"",s),
+            Tok::Const => "const",
+            Tok::Int => "int",
+            Tok::Void => "void",
+            Tok::If => "if",
+            Tok::Else => "else",
+            Tok::While => "while",
+            Tok::Break => "break",
+            Tok::Continue => "continue",
+            Tok::Return => "return",
+            Tok::LParen => "(",
+            Tok::RParen => ")",
+            Tok::LBrace => "{",
+            Tok::RBrace => "}",
+            Tok::LBracket => "[",
+            Tok::RBracket => "]",
+            Tok::Semi => ";",
+            Tok::Comma => ",",
+            Tok::Assign => "=",
+            Tok::Plus => "+",
+            Tok::Minus => "-",
+            Tok::Star => "*",
+            Tok::Slash => "/",
+            Tok::Percent => "%",
+            Tok::Not => "!",
+            Tok::Tilde => "~",
+            Tok::Lt => "<",
+            Tok::Gt => ">",
+            Tok::Le => "<=",
+            Tok::Ge => ">=",
+            Tok::Eq => "==",
+            Tok::Ne => "!=",
+            Tok::And => "&&",
+            Tok::Or => "||",
+        };
+        write!(f,"{}",s)
+    }
+}
+
+/// lalrpop 的外部 lexer 需要给出 (起始位置, token, 结束位置)
+pub type Spanned = Result<(usize, Tok, usize), Diagnostic>;
+
+/// 手写的 lexer. 遇到词法错误时记到 errors 里, 跳过出错的部分继续分析,
+/// 这样一次能报告多个错误, 语法分析也能接着进行
+pub struct Lexer<'a> {
+    source: &'a str,
+    pos: usize,
+    errors: &'a mut Vec<Diagnostic>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a str, errors: &'a mut Vec<Diagnostic>) -> Self {
+        Lexer { source, pos: 0, errors }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    fn error(&mut self, message: impl fmt::Display, start: usize, end: usize) {
+        self.errors.push(Diagnostic::new(message, Span::new(start, end)));
+    }
+
+    /// 跳过空白和注释, 块注释遇到第一个 */ 就结束
+    fn skip_trivia(&mut self) {
+        loop {
+            let rest=self.rest();
+            let trimmed=rest.trim_start();
+            self.pos+=rest.len()-trimmed.len();
+            if trimmed.starts_with("//") {
+                self.pos+=trimmed.find('\n').unwrap_or(trimmed.len());
+            }
+            else if let Some(comment)=trimmed.strip_prefix("/*") {
+                match comment.find("*/") {
+                    Some(end) => self.pos+=end+4,
+                    None => {
+                        self.error("unterminated block comment", self.pos, self.pos+2);
+                        self.pos=self.source.len();
+                    }
+                }
+            }
+            else {
+                return;
+            }
+        }
+    }
+
+    /// 整数字面量. 先按 C 的习惯把后面连着的字母数字都读进来, 再检查格式.
+    /// 十进制最大可以写到 2147483648, 这样才能写出 -2147483648;
+    /// 八进制和十六进制可以写到 0xFFFFFFFF, 按补码解释成负数
+    fn int_const(&mut self) -> Tok {
+        let start=self.pos;
+        let len=self.rest().find(|c: char| !c.is_ascii_alphanumeric() && c!='_').unwrap_or(self.rest().len());
+        let text=&self.rest()[..len];
+        self.pos+=len;
+        let (digits, radix, max)=if let Some(hex)=text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            (hex, 16, u32::MAX as u64)
+        }
+        else if text.len()>1 && text.starts_with('0') {
+            (&text[1..], 8, u32::MAX as u64)
+        }
+        else {
+            (text, 10, 1<<31)
+        };
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            self.error(format!("invalid integer literal `{}`",text), start, self.pos);
+            return Tok::IntConst(0);
+        }
+        // 每读一位就检查, 以免 u64 也溢出
+        let mut value: u64=0;
+        for c in digits.chars() {
+            value=value*radix as u64+c.to_digit(radix).unwrap() as u64;
+            if value>max {
+                self.error(format!("integer literal `{}` is out of range",text), start, self.pos);
+                return Tok::IntConst(0);
+            }
+        }
+        Tok::IntConst(value as u32 as i32)
+    }
+
+    /// 字符串字面量, 不能跨行
+    fn string_const(&mut self) -> Option<Tok> {
+        let start=self.pos;
+        let mut chars=self.rest().char_indices().skip(1);
+        while let Some((i, c))=chars.next() {
+            match c {
+                '"' => {
+                    let s=self.rest()[1..i].to_string();
+                    self.pos+=i+1;
+                    return Some(Tok::StringConst(s));
+                }
+                // 反斜杠后面直接换行也不能接着写到下一行
+                '\\' => {
+                    if let Some((_, '\n')) | None=chars.next() {
+                        break;
+                    }
+                }
+                '\n' => break,
+                _ => {}
+            }
+        }
+        let len=self.rest().find('\n').unwrap_or(self.rest().len());
+        self.pos+=len;
+        self.error("unterminated string literal", start, self.pos);
+        None
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Spanned;
+
+    fn next(&mut self) -> Option<Spanned> {
+        loop {
+            self.skip_trivia();
+            let start=self.pos;
+            let c=self.rest().chars().next()?;
+            let tok=if c.is_ascii_alphabetic() || c=='_' {
+                let len=self.rest().find(|c: char| !c.is_ascii_alphanumeric() && c!='_').unwrap_or(self.rest().len());
+                let ident=&self.rest()[..len];
+                self.pos+=len;
+                match ident {
+                    "const" => Tok::Const,
+                    "int" => Tok::Int,
+                    "void" => Tok::Void,
+                    "if" => Tok::If,
+                    "else" => Tok::Else,
+                    "while" => Tok::While,
+                    "break" => Tok::Break,
+                    "continue" => Tok::Continue,
+                    "return" => Tok::Return,
+                    _ => Tok::Ident(ident.to_string()),
+                }
+            }
+            else if c.is_ascii_digit() {
+                self.int_const()
+            }
+            else if c=='"' {
+                match self.string_const() {
+                    Some(tok) => tok,
+                    None => continue,
+                }
+            }
+            else {
+                let two=[("<=", Tok::Le), (">=", Tok::Ge), ("==", Tok::Eq), ("!=", Tok::Ne), ("&&", Tok::And), ("||", Tok::Or)]
+                    .into_iter().find(|(s, _)| self.rest().starts_with(s));
+                if let Some((s, tok))=two {
+                    self.pos+=s.len();
+                    tok
+                }
+                else {
+                    self.pos+=c.len_utf8();
+                    match c {
+                        '(' => Tok::LParen,
+                        ')' => Tok::RParen,
+                        '{' => Tok::LBrace,
+                        '}' => Tok::RBrace,
+                        '[' => Tok::LBracket,
+                        ']' => Tok::RBracket,
+                        ';' => Tok::Semi,
+                        ',' => Tok::Comma,
+                        '=' => Tok::Assign,
+                        '+' => Tok::Plus,
+                        '-' => Tok::Minus,
+                        '*' => Tok::Star,
+                        '/' => Tok::Slash,
+                        '%' => Tok::Percent,
+                        '!' => Tok::Not,
+                        '~' => Tok::Tilde,
+                        '<' => Tok::Lt,
+                        '>' => Tok::Gt,
+                        _ => {
+                            self.error(format!("unknown character `{}`",c), start, self.pos);
+                            continue;
+                        }
+                    }
+                }
+            };
+            return Some(Ok((start, tok, self.pos)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 分析整个 source, 返回得到的 token 和词法错误
+    fn lex(source: &str) -> (Vec<Tok>, Vec<Diagnostic>) {
+        let mut errors=Vec::new();
+        let toks=Lexer::new(source, &mut errors).map(|tok| tok.unwrap().1).collect();
+        (toks, errors)
+    }
+
+    #[test]
+    fn int_const_range() {
+        let (toks, errors)=lex("2147483647 2147483648 0x7fffffff 0xFFFFFFFF 037777777777 0");
+        assert!(errors.is_empty());
+        assert_eq!(toks, [i32::MAX, i32::MIN, i32::MAX, -1, -1, 0].map(Tok::IntConst));
+        for text in ["2147483649", "0x100000000", "040000000000", "99999999999999999999999"] {
+            let (toks, errors)=lex(text);
+            assert_eq!(toks, [Tok::IntConst(0)]);
+            assert_eq!(errors.len(), 1, "{}", text);
+            assert_eq!(errors[0].message, format!("integer literal `{}` is out of range",text));
+        }
+    }
+
+    #[test]
+    fn block_comment_ends_at_first_close() {
+        let (toks, errors)=lex("a /* x */ b /* y */ c /**/ d /* * / */ e");
+        assert!(errors.is_empty());
+        assert_eq!(toks, ["a", "b", "c", "d", "e"].map(|ident| Tok::Ident(ident.to_string())));
+    }
+
+    #[test]
+    fn unterminated_block_comment() {
+        let (toks, errors)=lex("a /* b */ c /* d");
+        assert_eq!(toks, [Tok::Ident("a".to_string()), Tok::Ident("c".to_string())]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "unterminated block comment");
+        assert_eq!(errors[0].span, Span::new(12, 14));
+    }
+
+    #[test]
+    fn string_const_cannot_span_lines() {
+        let (toks, errors)=lex("\"a\\\"b\" \"c\\\nd");
+        assert_eq!(toks, [Tok::StringConst("a\\\"b".to_string()), Tok::Ident("d".to_string())]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "unterminated string literal");
+    }
+}
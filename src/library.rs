@@ -0,0 +1,33 @@
+use crate::ast::Ty;
+
+/// putf 格式串后面最多能跟的参数个数
+pub const PUTF_ARGS: usize = 7;
+
+/// SysY 运行时库里的一个函数, sema 和 IR 生成都按这里的签名声明
+pub struct LibFunc {
+    pub name: &'static str,
+    pub ret: Ty,
+    pub params: Vec<Ty>,
+    /// 后面的参数可以不给. Koopa 没有可变参数, 声明时带上全部参数, 调用时没给的补 0
+    pub variadic: bool,
+}
+
+/// 运行时库里的所有函数.
+/// putf 固定带上格式串和 PUTF_ARGS 个 int, 正好放满 a0-a7
+pub fn library() -> Vec<LibFunc> {
+    let func=|name, ret, params| LibFunc { name, ret, params, variadic: false };
+    let ptr=|| Ty::Ptr(vec![]);
+    let mut putf=vec![Ty::Str];
+    putf.resize(PUTF_ARGS+1, Ty::Int);
+    vec![
+        func("getint", Ty::Int, vec![]),
+        func("getch", Ty::Int, vec![]),
+        func("getarray", Ty::Int, vec![ptr()]),
+        func("putint", Ty::Void, vec![Ty::Int]),
+        func("putch", Ty::Void, vec![Ty::Int]),
+        func("putarray", Ty::Void, vec![Ty::Int, ptr()]),
+        LibFunc { name: "putf", ret: Ty::Void, params: putf, variadic: true },
+        func("_sysy_starttime", Ty::Void, vec![Ty::Int]),
+        func("_sysy_stoptime", Ty::Void, vec![Ty::Int]),
+    ]
+}
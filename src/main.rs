@@ -1,40 +1,168 @@
 mod ast;
 mod asm;
+mod regalloc;
 mod irgen;
 mod eval;
-use crate::irgen::IR;
-use crate::asm::GenerateAsm;
-use koopa::front::Driver;
+mod symtab;
+mod diag;
+mod lexer;
+mod visit;
+mod desugar;
+mod sema;
+mod library;
+use crate::diag::{Diagnostic, from_parse_error};
+use crate::lexer::Lexer;
+use crate::irgen::IrGenContext;
+use crate::desugar::ExpandMacros;
+use crate::sema::Sema;
+use crate::visit::VisitorMut;
+use crate::asm::{GenerateAsm, AsmContext};
+use crate::regalloc::Allocator;
+use koopa::back::KoopaGenerator;
+use koopa::ir::Type;
 
 use lalrpop_util::lalrpop_mod;
 use std::env::args;
 use std::fs::read_to_string;
 use std::fs::write;
 use std::io::Result;
+use std::process::exit;
 
 // 引用 lalrpop 生成的解析器
 // 因为我们刚刚创建了 sysy.lalrpop, 所以模块名是 sysy
-lalrpop_mod!(sysy);
+lalrpop_mod!(#[allow(clippy::all)] sysy);
 
 fn main() -> Result<()> {
-    let mut args=args();
-    args.next();
-    let mode=args.next().unwrap();
-    let input=args.next().unwrap();
-    args.next();
-    let output=args.next().unwrap();
+    let mut args: Vec<String>=args().skip(1).collect();
+    // -O2 可以写在任何位置, 先拿出来, 剩下的按位置读.
+    // 有 -O2 时用图着色分配寄存器, 默认用线性扫描
+    let optimize=args.iter().any(|arg| arg=="-O2");
+    args.retain(|arg| arg!="-O2");
+    let allocator=if optimize { Allocator::GraphColoring } else { Allocator::LinearScan };
+    let (mode, file)=match &args[..] {
+        [mode, file, ..] if ["-tokens", "-ast", "-koopa", "-riscv"].contains(&mode.as_str()) => (mode.as_str(), file.as_str()),
+        _ => usage(),
+    };
 
-    let input=read_to_string(input)?;
-    let ast=sysy::CompUnitParser::new().parse(&input).unwrap();
+    let input=read_to_string(file).unwrap_or_else(|err| {
+        eprintln!("error: cannot read {}: {}",file,err);
+        exit(1);
+    });
+    if mode=="-tokens" {
+        dump_tokens(file, &input);
+        return Ok(());
+    }
+    // 词法错误和能恢复的语法错误都先记下来继续解析, 最后按位置顺序一起报告
+    let mut lex_errors=Vec::new();
+    let mut errors=Vec::new();
+    let result=sysy::CompUnitParser::new().parse(&mut errors, Lexer::new(&input, &mut lex_errors));
+    let mut diagnostics=lex_errors;
+    diagnostics.extend(errors.into_iter().map(|err| from_parse_error(err.error)));
+    // 不能恢复的语法错误也和其他错误一起按位置排好再报告
+    let result=result.map_err(|err| diagnostics.push(from_parse_error(err)));
+    diagnostics.sort_by_key(|diag| diag.span.start);
+    let recovered=!diagnostics.is_empty();
+    for diag in diagnostics {
+        eprint!("{}",diag.render(file, &input));
+    }
+    let Ok(mut ast)=result else { exit(1) };
+    let Ok(())=ExpandMacros::new(&input).visit_comp_unit_mut(&mut ast);
+    // 跳过出错的部分以后剩下的代码照样做语义检查, 但不报告因此找不到的名字
+    if let Err(err)=Sema::new(recovered).visit_comp_unit_mut(&mut ast) {
+        report(err, file, &input);
+    }
+    if recovered {
+        exit(1);
+    }
 
-    match &mode as &str {
+    match mode {
         "-ast" => println!("{:#?}",ast),
-        "-koopa" => write(&output, ast.irdump().irstr)?,
-        "-riscv" => write(&output, Driver::from(ast.irdump().irstr).generate_program().unwrap().generate(None))?,
+        "-koopa" => {
+            let output=output_path(&args);
+            let (program, _)=IrGenContext::default().generate(&ast);
+            let mut gen=KoopaGenerator::new(Vec::new());
+            gen.generate_on(&program)?;
+            write(&output, gen.writer())?
+        }
+        "-riscv" => {
+            let output=output_path(&args);
+            // RV32 上指针占 4 字节
+            Type::set_ptr_size(4);
+            let (program, strings)=IrGenContext::default().generate(&ast);
+            write(&output, program.generate(&mut AsmContext::new(&program, &strings, allocator)))?
+        }
         _ => {}
     }
 
     //println!("{}",ast.irdump().irstr);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// 生成 IR 或汇编时要用 -o 给出输出文件
+fn output_path(args: &[String]) -> String {
+    match &args[2..] {
+        [flag, output, ..] if flag=="-o" => output.clone(),
+        _ => usage(),
+    }
+}
+
+/// 命令行参数不对时输出用法并以非零状态退出
+fn usage() -> ! {
+    eprintln!("usage: compile-proj [-O2] (-koopa | -riscv) <input> -o <output>");
+    eprintln!("       compile-proj (-tokens | -ast) <input>");
+    exit(1);
+}
+
+/// 每行输出一个 token 以及它的起始位置 (行:列), 有词法错误时最后报告出来
+fn dump_tokens(file: &str, source: &str) {
+    let mut errors=Vec::new();
+    for (start, tok, end) in Lexer::new(source, &mut errors).flatten() {
+        let line=source[..start].matches('\n').count()+1;
+        let col=source[..start].rfind('\n').map_or(start, |i| start-i-1)+1;
+        println!("{}:{}\t{:?}\t{:?}",line,col,&source[start..end],tok);
+    }
+    for err in &errors {
+        eprint!("{}",err.render(file, source));
+    }
+    if !errors.is_empty() {
+        exit(1);
+    }
+}
+
+/// 输出错误信息并以非零状态退出
+fn report(err: Diagnostic, file: &str, source: &str) -> ! {
+    eprint!("{}",err.render(file, source));
+    exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 在同一个进程里编译 source, 返回 Koopa IR 和汇编
+    fn compile(source: &str) -> (String, String) {
+        let mut lex_errors=Vec::new();
+        let mut ast=sysy::CompUnitParser::new().parse(&mut Vec::new(), Lexer::new(source, &mut lex_errors)).unwrap();
+        let Ok(())=ExpandMacros::new(source).visit_comp_unit_mut(&mut ast);
+        Sema::new(false).visit_comp_unit_mut(&mut ast).unwrap();
+        let (program, _)=IrGenContext::default().generate(&ast);
+        let mut gen=KoopaGenerator::new(Vec::new());
+        gen.generate_on(&program).unwrap();
+        let koopa=String::from_utf8(gen.writer()).unwrap();
+        let (program, strings)=IrGenContext::default().generate(&ast);
+        let asm=program.generate(&mut AsmContext::new(&program, &strings, Allocator::LinearScan));
+        (koopa, asm)
+    }
+
+    #[test]
+    fn compile_twice_in_one_process() {
+        Type::set_ptr_size(4);
+        let first="const int N = 3;\nint a[N] = {1, 2};\nint f(int x) { int y = x * N; return y + a[1]; }\nint main() { putf(\"%d\\n\", f(2)); return f(1); }\n";
+        let second="const int N = 5;\nint g(int b[]) { return b[0]; }\nint main() { int a[N] = {}; a[0] = N; putf(\"ok\\n\"); return g(a); }\n";
+        let expected=compile(first);
+        compile(second);
+        assert_eq!(compile(first), expected);
+        assert_eq!(compile(second), compile(second));
+    }
+}
@@ -0,0 +1,524 @@
+use std::collections::{HashMap, HashSet};
+
+use koopa::ir::{BasicBlock, FunctionData, Value, ValueKind};
+
+// t0-t2 留给生成每条指令时读写栈上的值和算地址用, 不参与分配
+
+/// 调用者保存的临时寄存器, 活跃区间跨过函数调用的值不能放在这里
+const TEMP_REGS: [&str; 4]=["t3", "t4", "t5", "t6"];
+/// 传参用的寄存器, 只分给和任何函数调用都不重叠的值, 这样准备参数时不会互相覆盖
+const ARG_REGS: [&str; 8]=["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"];
+/// 被调用者保存的寄存器, 用到的要在序言里保存
+const SAVED_REGS: [&str; 12]=["s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11"];
+
+/// 用哪种算法分配寄存器
+#[derive(Clone, Copy)]
+pub enum Allocator {
+    /// 线性扫描, 分配得快
+    LinearScan,
+    /// 图着色 (Chaitin-Briggs), -O2 时使用
+    GraphColoring,
+}
+
+impl Allocator {
+    pub fn allocate(self, func: &FunctionData) -> Allocation {
+        match self {
+            Allocator::LinearScan => linear_scan(func),
+            Allocator::GraphColoring => graph_coloring(func),
+        }
+    }
+}
+
+/// 一个函数的寄存器分配结果, 不在 regs 里的值放在栈上
+#[derive(Default)]
+pub struct Allocation {
+    pub regs: HashMap<Value, &'static str>,
+    /// 放在栈上的值, 按溢出的先后排列
+    pub spilled: Vec<Value>,
+}
+
+impl Allocation {
+    /// 用到的被调用者保存的寄存器
+    pub fn saved_regs(&self) -> Vec<&'static str> {
+        SAVED_REGS.iter().copied().filter(|reg| self.regs.values().any(|r| r==reg)).collect()
+    }
+}
+
+/// 需要分配位置的值: 布局里有结果的指令 (alloc 除外, 它的值就是栈帧里的地址), 基本块参数和函数的前 8 个参数.
+/// 第 8 个以后的参数本来就在调用者的栈帧里, 用的时候直接去读
+fn needs_location(func: &FunctionData, value: Value) -> bool {
+    if value.is_global() {
+        return false;
+    }
+    let data=func.dfg().value(value);
+    match data.kind() {
+        ValueKind::Alloc(_) => false,
+        ValueKind::FuncArgRef(arg) => arg.index()<8,
+        ValueKind::BlockArgRef(_) => true,
+        _ => !data.ty().is_unit() && func.layout().parent_bb(value).is_some(),
+    }
+}
+
+/// inst 用到的需要分配位置的值
+fn uses(func: &FunctionData, inst: Value) -> Vec<Value> {
+    func.dfg().value(inst).kind().value_uses().filter(|&value| needs_location(func, value)).collect()
+}
+
+/// 基本块的后继, 由最后一条跳转指令决定
+fn successors(func: &FunctionData, bb: BasicBlock) -> Vec<BasicBlock> {
+    let last=func.layout().bbs().node(&bb).unwrap().insts().back_key();
+    match last.map(|&inst| func.dfg().value(inst).kind()) {
+        Some(ValueKind::Branch(branch)) => vec![branch.true_bb(), branch.false_bb()],
+        Some(ValueKind::Jump(jump)) => vec![jump.target()],
+        _ => Vec::new(),
+    }
+}
+
+/// 每个基本块入口和出口处活跃的值
+pub struct Liveness {
+    pub live_in: HashMap<BasicBlock, HashSet<Value>>,
+    pub live_out: HashMap<BasicBlock, HashSet<Value>>,
+}
+
+/// 反复迭代数据流方程直到不再变化
+pub fn liveness(func: &FunctionData) -> Liveness {
+    // 块里先用后定义的值, 以及块里定义的值
+    let mut gen=HashMap::new();
+    let mut kill=HashMap::new();
+    for (&bb, node) in func.layout().bbs() {
+        let mut used=HashSet::new();
+        // 基本块参数在块开头定义
+        let mut defined: HashSet<Value>=func.dfg().bb(bb).params().iter().copied().collect();
+        for &inst in node.insts().keys() {
+            used.extend(uses(func, inst).into_iter().filter(|value| !defined.contains(value)));
+            defined.insert(inst);
+        }
+        gen.insert(bb, used);
+        kill.insert(bb, defined);
+    }
+    let bbs: Vec<BasicBlock>=func.layout().bbs().keys().copied().collect();
+    let mut live_in: HashMap<BasicBlock, HashSet<Value>>=bbs.iter().map(|&bb| (bb, HashSet::new())).collect();
+    let mut live_out: HashMap<BasicBlock, HashSet<Value>>=live_in.clone();
+    let mut changed=true;
+    while changed {
+        changed=false;
+        // 倒着扫收敛得快一些
+        for &bb in bbs.iter().rev() {
+            let out: HashSet<Value>=successors(func, bb).iter().flat_map(|succ| live_in[succ].iter().copied()).collect();
+            let mut inn: HashSet<Value>=out.difference(&kill[&bb]).copied().collect();
+            inn.extend(gen[&bb].iter().copied());
+            if inn.len()!=live_in[&bb].len() || out.len()!=live_out[&bb].len() {
+                changed=true;
+                live_in.insert(bb, inn);
+                live_out.insert(bb, out);
+            }
+        }
+    }
+    Liveness { live_in, live_out }
+}
+
+/// 活跃区间, 按布局顺序给指令编号, 参数定义在 0, 第一条指令是 1
+struct Interval {
+    value: Value,
+    start: usize,
+    end: usize,
+}
+
+/// 计算每个值的活跃区间, 以及所有函数调用的位置
+fn intervals(func: &FunctionData) -> (Vec<Interval>, Vec<usize>) {
+    let liveness=liveness(func);
+    let mut ranges: HashMap<Value, (usize, usize)>=HashMap::new();
+    let mut extend=|value: Value, pos: usize| {
+        let range=ranges.entry(value).or_insert((pos, pos));
+        range.0=range.0.min(pos);
+        range.1=range.1.max(pos);
+    };
+    for &param in func.params() {
+        if needs_location(func, param) {
+            extend(param, 0);
+        }
+    }
+    let mut calls=Vec::new();
+    let mut pos=0;
+    for (&bb, node) in func.layout().bbs() {
+        let start=pos+1;
+        for &param in func.dfg().bb(bb).params() {
+            extend(param, start);
+        }
+        for &inst in node.insts().keys() {
+            pos+=1;
+            for value in uses(func, inst) {
+                extend(value, pos);
+            }
+            if needs_location(func, inst) {
+                extend(inst, pos);
+            }
+            if let ValueKind::Call(_)=func.dfg().value(inst).kind() {
+                calls.push(pos);
+            }
+        }
+        for &value in &liveness.live_in[&bb] {
+            extend(value, start);
+        }
+        for &value in &liveness.live_out[&bb] {
+            extend(value, pos);
+        }
+    }
+    let mut intervals: Vec<Interval>=ranges.into_iter().map(|(value, (start, end))| Interval { value, start, end }).collect();
+    intervals.sort_by_key(|interval| (interval.start, interval.end));
+    (intervals, calls)
+}
+
+/// 线性扫描分配寄存器. 按起点顺序处理活跃区间, 没有空闲寄存器时
+/// 溢出结束得最晚的那个区间, 溢出的值整个放在栈上
+fn linear_scan(func: &FunctionData) -> Allocation {
+    let (intervals, calls)=intervals(func);
+    let mut alloc=Allocation::default();
+    // 正在占用寄存器的区间: (结束位置, 值, 寄存器)
+    let mut active: Vec<(usize, Value, &'static str)>=Vec::new();
+    for interval in &intervals {
+        active.retain(|&(end, _, _)| end>=interval.start);
+        // 跨过函数调用的值只能放在被调用者保存的寄存器里
+        let crosses=calls.iter().any(|&call| interval.start<call && call<interval.end);
+        let touches=calls.iter().any(|&call| interval.start<=call && call<=interval.end);
+        let is_param=interval.start==0;
+        let candidates: Vec<&'static str>=if crosses {
+            SAVED_REGS.to_vec()
+        }
+        else if touches || is_param {
+            TEMP_REGS.iter().chain(SAVED_REGS.iter()).copied().collect()
+        }
+        else {
+            ARG_REGS.iter().chain(TEMP_REGS.iter()).chain(SAVED_REGS.iter()).copied().collect()
+        };
+        let free=candidates.iter().copied().find(|reg| active.iter().all(|&(_, _, r)| r!=*reg));
+        if let Some(reg)=free {
+            alloc.regs.insert(interval.value, reg);
+            active.push((interval.end, interval.value, reg));
+            continue;
+        }
+        let victim=active.iter().enumerate()
+            .filter(|(_, &(_, _, reg))| candidates.contains(&reg))
+            .max_by_key(|(_, &(end, _, _))| end)
+            .map(|(i, &(end, _, _))| (i, end));
+        match victim {
+            Some((i, end)) if end>interval.end => {
+                let (_, value, reg)=active.remove(i);
+                alloc.regs.remove(&value);
+                alloc.spilled.push(value);
+                alloc.regs.insert(interval.value, reg);
+                active.push((interval.end, interval.value, reg));
+            }
+            _ => alloc.spilled.push(interval.value),
+        }
+    }
+    alloc
+}
+
+/// 每个基本块所在的循环层数. 跳回 DFS 栈上的块的边是回边, 回边确定一个自然循环,
+/// 循环体是不经过循环头就能走到回边起点的块
+fn loop_depth(func: &FunctionData) -> HashMap<BasicBlock, u32> {
+    let mut preds: HashMap<BasicBlock, Vec<BasicBlock>>=HashMap::new();
+    for &bb in func.layout().bbs().keys() {
+        for succ in successors(func, bb) {
+            preds.entry(succ).or_default().push(bb);
+        }
+    }
+    // 每个循环头对应的循环体, 同一个循环头的几条回边合成一个循环
+    let mut loops: HashMap<BasicBlock, HashSet<BasicBlock>>=HashMap::new();
+    let entry=func.layout().entry_bb().unwrap();
+    let mut visited=HashSet::from([entry]);
+    let mut on_stack=HashSet::from([entry]);
+    let mut stack=vec![(entry, successors(func, entry), 0)];
+    while let Some((bb, succs, i))=stack.last_mut() {
+        if *i==succs.len() {
+            on_stack.remove(bb);
+            stack.pop();
+            continue;
+        }
+        let (bb, succ)=(*bb, succs[*i]);
+        *i+=1;
+        if on_stack.contains(&succ) {
+            let body=loops.entry(succ).or_insert_with(|| HashSet::from([succ]));
+            let mut work=vec![bb];
+            while let Some(block)=work.pop() {
+                if body.insert(block) {
+                    work.extend(preds[&block].iter().copied());
+                }
+            }
+        }
+        else if visited.insert(succ) {
+            on_stack.insert(succ);
+            stack.push((succ, successors(func, succ), 0));
+        }
+    }
+    let mut depth=HashMap::new();
+    for body in loops.values() {
+        for &bb in body {
+            *depth.entry(bb).or_insert(0)+=1;
+        }
+    }
+    depth
+}
+
+/// 冲突图, 前面 REGS 个节点是物理寄存器, 后面每个节点是一个值.
+/// 物理寄存器的颜色固定, 不记录它们的邻居
+struct Graph {
+    regs: Vec<&'static str>,
+    values: Vec<Value>,
+    index: HashMap<Value, usize>,
+    edges: HashSet<(usize, usize)>,
+    adj: Vec<HashSet<usize>>,
+    // 把这个值放到栈上的代价, 每次定义和使用按所在循环的深度加权
+    cost: Vec<f64>,
+    // 希望分到同一个寄存器的节点对和它们的权重, 分到一起就省掉了一条 mv
+    moves: Vec<(usize, usize, f64)>,
+    // 合并以后节点的代表
+    alias: Vec<usize>,
+}
+
+impl Graph {
+    fn new(values: Vec<Value>) -> Self {
+        let regs: Vec<&'static str>=TEMP_REGS.iter().chain(ARG_REGS.iter()).chain(SAVED_REGS.iter()).copied().collect();
+        let n=regs.len()+values.len();
+        let index=values.iter().enumerate().map(|(i, &value)| (value, regs.len()+i)).collect();
+        Graph { regs, values, index, edges: HashSet::new(), adj: vec![HashSet::new(); n], cost: vec![0.0; n], moves: Vec::new(), alias: (0..n).collect() }
+    }
+
+    fn is_reg(&self, node: usize) -> bool {
+        node<self.regs.len()
+    }
+
+    fn reg(&self, name: &str) -> usize {
+        self.regs.iter().position(|&reg| reg==name).unwrap()
+    }
+
+    fn add_edge(&mut self, u: usize, v: usize) {
+        if u==v || self.edges.contains(&(u, v)) {
+            return;
+        }
+        self.edges.insert((u, v));
+        self.edges.insert((v, u));
+        if !self.is_reg(u) {
+            self.adj[u].insert(v);
+        }
+        if !self.is_reg(v) {
+            self.adj[v].insert(u);
+        }
+    }
+
+    /// 物理寄存器的度数看作无穷大
+    fn degree(&self, node: usize) -> usize {
+        if self.is_reg(node) { usize::MAX } else { self.adj[node].len() }
+    }
+
+    fn find(&self, mut node: usize) -> usize {
+        while self.alias[node]!=node {
+            node=self.alias[node];
+        }
+        node
+    }
+
+    /// Briggs: 合并后度数不小于 K 的邻居少于 K 个, 合并不会让图变得不能着色
+    fn briggs(&self, u: usize, v: usize) -> bool {
+        let k=self.regs.len();
+        self.adj[u].union(&self.adj[v]).filter(|&&t| self.degree(t)>=k).count()<k
+    }
+
+    /// George: 和物理寄存器 u 合并时, v 的每个邻居要么度数小于 K, 要么本来就和 u 冲突
+    fn george(&self, u: usize, v: usize) -> bool {
+        let k=self.regs.len();
+        self.adj[v].iter().all(|&t| self.degree(t)<k || self.edges.contains(&(t, u)))
+    }
+
+    fn combine(&mut self, u: usize, v: usize) {
+        self.alias[v]=u;
+        for t in std::mem::take(&mut self.adj[v]) {
+            self.add_edge(t, u);
+            self.adj[t].remove(&v);
+        }
+        self.cost[u]+=self.cost[v];
+    }
+
+    /// 保守合并, 直到没有能合并的 move
+    fn coalesce(&mut self) {
+        let mut moves=self.moves.clone();
+        moves.sort_by(|a, b| b.2.total_cmp(&a.2));
+        let mut changed=true;
+        while changed {
+            changed=false;
+            for &(x, y, _) in &moves {
+                let (mut u, mut v)=(self.find(x), self.find(y));
+                if self.is_reg(v) {
+                    std::mem::swap(&mut u, &mut v);
+                }
+                if u==v || self.is_reg(v) || self.edges.contains(&(u, v)) {
+                    continue;
+                }
+                let ok=if self.is_reg(u) { self.george(u, v) } else { self.briggs(u, v) };
+                if ok {
+                    self.combine(u, v);
+                    changed=true;
+                }
+            }
+        }
+    }
+
+    /// 化简以后按相反的顺序着色. 找不到度数小于 K 的节点时选代价/度数最小的
+    /// 乐观地压栈, 真的染不上色再放到栈上
+    fn color(&self) -> Vec<Option<&'static str>> {
+        let k=self.regs.len();
+        let mut degree: Vec<usize>=self.adj.iter().map(|adj| adj.len()).collect();
+        let mut remaining: Vec<usize>=(k..self.alias.len()).filter(|&node| self.alias[node]==node).collect();
+        let mut removed=vec![false; self.alias.len()];
+        let mut stack=Vec::new();
+        while !remaining.is_empty() {
+            let pick=remaining.iter().position(|&node| degree[node]<k).unwrap_or_else(|| {
+                let spill_cost=|node: usize| self.cost[node]/degree[node] as f64;
+                (0..remaining.len()).min_by(|&a, &b| spill_cost(remaining[a]).total_cmp(&spill_cost(remaining[b]))).unwrap()
+            });
+            let node=remaining.swap_remove(pick);
+            removed[node]=true;
+            for &t in &self.adj[node] {
+                if !self.is_reg(t) && !removed[t] {
+                    degree[t]-=1;
+                }
+            }
+            stack.push(node);
+        }
+        let mut partners: HashMap<usize, Vec<usize>>=HashMap::new();
+        for &(x, y, _) in &self.moves {
+            let (u, v)=(self.find(x), self.find(y));
+            partners.entry(u).or_default().push(v);
+            partners.entry(v).or_default().push(u);
+        }
+        let mut colors: Vec<Option<&'static str>>=(0..self.alias.len()).map(|node| self.regs.get(node).copied()).collect();
+        while let Some(node)=stack.pop() {
+            let used: HashSet<&str>=self.adj[node].iter().filter_map(|&t| colors[t]).collect();
+            // 和它有 move 的节点已经有了能用的颜色就跟着用
+            let preferred=partners.get(&node).into_iter().flatten()
+                .filter_map(|&partner| colors[partner])
+                .find(|reg| !used.contains(reg));
+            colors[node]=preferred.or_else(|| self.regs.iter().copied().find(|reg| !used.contains(reg)));
+        }
+        colors
+    }
+}
+
+/// 图着色分配寄存器 (Chaitin-Briggs). 调用约定用物理寄存器节点表示:
+/// 跨过调用的值和所有调用者保存的寄存器冲突, 传参和返回值是和 a0-a7 之间的 move
+fn graph_coloring(func: &FunctionData) -> Allocation {
+    let liveness=liveness(func);
+    let depth=loop_depth(func);
+    let mut values: Vec<Value>=func.params().iter().copied().filter(|&param| needs_location(func, param)).collect();
+    for (&bb, node) in func.layout().bbs() {
+        values.extend(func.dfg().bb(bb).params().iter().copied());
+        values.extend(node.insts().keys().copied().filter(|&inst| needs_location(func, inst)));
+    }
+    let mut graph=Graph::new(values);
+    let caller_saved: Vec<usize>=TEMP_REGS.iter().chain(ARG_REGS.iter()).map(|reg| graph.reg(reg)).collect();
+    let arg_regs: Vec<usize>=ARG_REGS.iter().map(|reg| graph.reg(reg)).collect();
+    let entry=func.layout().entry_bb().unwrap();
+    for (&bb, node) in func.layout().bbs() {
+        let weight=10f64.powi(depth.get(&bb).copied().unwrap_or(0).min(8) as i32);
+        let mut live=liveness.live_out[&bb].clone();
+        let insts: Vec<Value>=node.insts().keys().copied().collect();
+        for &inst in insts.iter().rev() {
+            let id=graph.index.get(&inst).copied();
+            if let Some(id)=id {
+                live.remove(&inst);
+                for value in &live {
+                    graph.add_edge(id, graph.index[value]);
+                }
+                graph.cost[id]+=weight;
+            }
+            match func.dfg().value(inst).kind() {
+                ValueKind::Call(call) => {
+                    // 跨过调用的值不能放在调用者保存的寄存器里
+                    for value in &live {
+                        for &reg in &caller_saved {
+                            graph.add_edge(graph.index[value], reg);
+                        }
+                    }
+                    // 准备参数时依次写 a0-a7, 参数只能留在自己要去的那个 a 寄存器里
+                    for (i, &arg) in call.args().iter().enumerate() {
+                        let Some(&arg_id)=graph.index.get(&arg) else { continue };
+                        let only=i<8 && call.args().iter().filter(|&&other| other==arg).count()==1;
+                        for (j, &reg) in arg_regs.iter().enumerate() {
+                            if !only || j!=i {
+                                graph.add_edge(arg_id, reg);
+                            }
+                        }
+                        if only {
+                            graph.moves.push((arg_id, arg_regs[i], weight));
+                        }
+                    }
+                    if let Some(id)=id {
+                        graph.moves.push((id, arg_regs[0], weight));
+                    }
+                }
+                ValueKind::Return(ret) => {
+                    if let Some(&id)=ret.value().and_then(|value| graph.index.get(&value)) {
+                        graph.moves.push((id, arg_regs[0], weight));
+                    }
+                }
+                ValueKind::Branch(branch) => {
+                    for (target, args) in [(branch.true_bb(), branch.true_args()), (branch.false_bb(), branch.false_args())] {
+                        block_arg_moves(&mut graph, func, target, args, weight);
+                    }
+                }
+                ValueKind::Jump(jump) => block_arg_moves(&mut graph, func, jump.target(), jump.args(), weight),
+                _ => {}
+            }
+            for value in uses(func, inst) {
+                graph.cost[graph.index[&value]]+=weight;
+                live.insert(value);
+            }
+        }
+        // 基本块参数在块开头同时定义, 函数参数在入口块之前同时定义
+        let mut params: Vec<Value>=func.dfg().bb(bb).params().to_vec();
+        if bb==entry {
+            params.extend(func.params().iter().copied().filter(|param| graph.index.contains_key(param)));
+        }
+        for param in &params {
+            live.remove(param);
+        }
+        for (i, param) in params.iter().enumerate() {
+            let id=graph.index[param];
+            graph.cost[id]+=weight;
+            for value in live.iter().chain(params[i+1..].iter()) {
+                graph.add_edge(id, graph.index[value]);
+            }
+        }
+    }
+    // 序言里依次把 a0-a7 挪到参数的位置, 参数只能留在自己传进来的那个 a 寄存器里
+    for (i, param) in func.params().iter().take(8).enumerate() {
+        let id=graph.index[param];
+        for (j, &reg) in arg_regs.iter().enumerate() {
+            if j!=i {
+                graph.add_edge(id, reg);
+            }
+        }
+        graph.moves.push((id, arg_regs[i], 1.0));
+    }
+
+    graph.coalesce();
+    let colors=graph.color();
+    let mut alloc=Allocation::default();
+    for (i, &value) in graph.values.iter().enumerate() {
+        match colors[graph.find(graph.regs.len()+i)] {
+            Some(reg) => { alloc.regs.insert(value, reg); }
+            None => alloc.spilled.push(value),
+        }
+    }
+    alloc
+}
+
+/// 跳到 target 时实参赋给基本块参数, 是一组 move
+fn block_arg_moves(graph: &mut Graph, func: &FunctionData, target: BasicBlock, args: &[Value], weight: f64) {
+    for (param, arg) in func.dfg().bb(target).params().iter().zip(args) {
+        if let Some(&arg_id)=graph.index.get(arg) {
+            graph.moves.push((arg_id, graph.index[param], weight));
+        }
+    }
+}
@@ -0,0 +1,510 @@
+use crate::ast::*;
+use crate::diag::Diagnostic;
+use crate::eval::{Constant, Evaluate, EvalError};
+use crate::library::{library, PUTF_ARGS};
+use crate::symtab::SymbolTable;
+use crate::visit::{VisitorMut, walk_block_mut, walk_comp_unit_mut};
+use std::fmt;
+
+/// 语义分析时名字对应的东西
+#[derive(Debug, Clone)]
+enum Def {
+    /// 常量, 记录它的值
+    Const(i32),
+    /// 常量数组, 记录每一维的长度和按行优先展开的元素
+    ConstArray { dims: Vec<usize>, values: Vec<i32> },
+    /// 变量, 数组或数组参数, 记录它的类型
+    Var(Ty),
+    /// 函数, 记录返回值 (int 或 void) 和每个参数的类型, 可变参数的只有运行时库里的 putf
+    Func { ret: Ty, params: Vec<Ty>, variadic: bool },
+}
+
+impl Constant for Def {
+    fn constant(&self) -> Option<(&[usize], &[i32])> {
+        match self {
+            Def::Const(value) => Some((&[], std::slice::from_ref(value))),
+            Def::ConstArray { dims, values } => Some((dims, values)),
+            Def::Var(_) | Def::Func { .. } => None,
+        }
+    }
+}
+
+/// 语义错误
+#[derive(Debug)]
+pub enum SemaError {
+    /// 同一作用域内重复定义
+    Redefined(String),
+    /// 使用了未声明的标识符
+    Undeclared(String),
+    /// 给常量赋值
+    AssignToConst(String),
+    /// 循环外的 break
+    BreakOutsideLoop,
+    /// 循环外的 continue
+    ContinueOutsideLoop,
+    /// 把函数当成变量用
+    NotAVariable(String),
+    /// 调用的不是函数
+    NotAFunction(String),
+    /// 调用时参数个数不对: (函数名, 需要的个数, 实际的个数)
+    ArgCount(String, usize, usize),
+    /// 在表达式里使用 void 函数的返回值
+    VoidValue(String),
+    /// void 函数返回了值
+    ReturnValueInVoid(String),
+    /// 非 void 函数没有返回值
+    MissingReturnValue(String),
+    /// 常量或全局变量的初始值不能在编译期算出来
+    ConstInit(String, EvalError),
+    /// 数组的长度不是正的常量
+    ArrayDim(String),
+    /// 初始化列表和变量的类型对不上
+    BadInit(String),
+    /// 下标比数组的维数还多
+    TooManyIndices(String),
+    /// 把没有取到元素的数组当成值用
+    ArrayValue(String),
+    /// 实参和形参的类型对不上: (函数名, 第几个参数, 形参类型, 实参类型)
+    ArgType(String, usize, Ty, Ty),
+    /// 在 putf 的格式串以外的地方用了字符串
+    StringLiteral,
+    /// putf 的参数不对
+    PutfArgs,
+}
+
+impl SemaError {
+    /// 在源码的 span 处报告这个错误
+    pub fn at(self, span: Span) -> Diagnostic {
+        Diagnostic::new(self, span)
+    }
+}
+
+impl fmt::Display for SemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SemaError::Redefined(ident) => write!(f,"redefinition of `{}`",ident),
+            SemaError::Undeclared(ident) => write!(f,"use of undeclared identifier `{}`",ident),
+            SemaError::AssignToConst(ident) => write!(f,"cannot assign to constant `{}`",ident),
+            SemaError::BreakOutsideLoop => write!(f,"`break` outside of a loop"),
+            SemaError::ContinueOutsideLoop => write!(f,"`continue` outside of a loop"),
+            SemaError::NotAVariable(ident) => write!(f,"`{}` is a function, not a variable",ident),
+            SemaError::NotAFunction(ident) => write!(f,"`{}` is not a function",ident),
+            SemaError::ArgCount(ident, expected, found) => write!(f,"function `{}` takes {} argument(s) but {} were supplied",ident,expected,found),
+            SemaError::VoidValue(ident) => write!(f,"void function `{}` does not return a value",ident),
+            SemaError::ReturnValueInVoid(ident) => write!(f,"void function `{}` should not return a value",ident),
+            SemaError::MissingReturnValue(ident) => write!(f,"non-void function `{}` should return a value",ident),
+            SemaError::ConstInit(ident, err) => write!(f,"invalid initializer for `{}`: {}",ident,err),
+            SemaError::ArrayDim(ident) => write!(f,"size of array `{}` must be a positive constant",ident),
+            SemaError::BadInit(ident) => write!(f,"initializer list does not match the type of `{}`",ident),
+            SemaError::TooManyIndices(ident) => write!(f,"too many indices for `{}`",ident),
+            SemaError::ArrayValue(ident) => write!(f,"array `{}` cannot be used as a value",ident),
+            SemaError::StringLiteral => write!(f,"string literals can only be used as the format of `putf`"),
+            SemaError::PutfArgs => write!(f,"`putf` takes a string literal followed by at most {} integers",PUTF_ARGS),
+            SemaError::ArgType(ident, index, expected, found) => write!(f,"mismatched types for argument {} of `{}`: expected `{}`, found `{}`",index+1,ident,expected,found),
+        }
+    }
+}
+
+/// 语义分析: 在 IR 生成之前检查名字, 类型, 常量和控制流,
+/// 同时给 AST 填上 IR 生成需要的信息:
+/// 每个表达式和定义的类型, 以及按数组元素展开并补齐了 0 的初始化列表,
+/// 常量和全局变量的初始值都算成了字面量
+#[derive(Default)]
+pub struct Sema {
+    symbols: SymbolTable<Def>,
+    // 当前函数的名字以及它是否返回 void
+    func: String,
+    func_void: bool,
+    // 外层循环的层数
+    loops: usize,
+    // 语法分析时有出错的代码被跳过了, 以及是否因此遇到了未声明的名字
+    recovered: bool,
+    cascade: bool,
+}
+
+impl Sema {
+    /// recovered 表示语法分析时跳过了出错的代码
+    pub fn new(recovered: bool) -> Self {
+        Sema { recovered, ..Default::default() }
+    }
+
+    /// 在全局作用域里定义运行时库中的函数
+    fn declare_library(&mut self) {
+        for func in library() {
+            self.symbols.insert(func.name, Def::Func { ret: func.ret, params: func.params, variadic: func.variadic });
+        }
+        // starttime 和 stoptime 是宏, 在 desugar 里展开成 _sysy_starttime 和 _sysy_stoptime
+        for ident in ["starttime", "stoptime"] {
+            self.symbols.insert(ident, Def::Func { ret: Ty::Void, params: vec![], variadic: false });
+        }
+    }
+
+    fn define(&mut self, ident: &str, def: Def, span: Span) -> Result<(), Diagnostic> {
+        if !self.symbols.insert(ident, def) {
+            return Err(SemaError::Redefined(ident.to_string()).at(span));
+        }
+        Ok(())
+    }
+
+    /// 计算数组每一维的长度, 不是数组时为空
+    fn array_dims(&mut self, ident: &str, dims: &mut [Expr]) -> Result<Vec<usize>, Diagnostic> {
+        let mut lens=Vec::new();
+        for dim in dims.iter_mut() {
+            self.check_int(dim)?;
+            match dim.eval(&self.symbols) {
+                Ok(len) if len>0 => lens.push(len as usize),
+                Err(err) if err.span().is_some() => return Err(eval_error(err)),
+                _ => return Err(SemaError::ArrayDim(ident.to_string()).at(dim.span)),
+            }
+        }
+        Ok(lens)
+    }
+
+    /// 检查初始化列表, 展开后检查每个元素都是 int; constant 表示初始值必须能在编译期算出来,
+    /// 此时把每个元素换成算出来的值
+    fn init_exps<T: InitList>(&mut self, ident: &str, init: T, dims: &[usize], constant: bool) -> Result<Vec<Expr>, Diagnostic> {
+        let span=init.span();
+        let mut exps=Vec::new();
+        match init.split() {
+            Ok(exp) if dims.is_empty() => exps.push(Some(exp)),
+            Err(list) if !dims.is_empty() => flatten(ident, list, dims, &mut exps)?,
+            _ => return Err(SemaError::BadInit(ident.to_string()).at(span)),
+        }
+        let mut values=Vec::new();
+        for exp in exps {
+            let mut exp=exp.unwrap_or_else(|| Expr::new(ExprKind::Lit(0), span));
+            self.check_int(&mut exp)?;
+            if constant {
+                let value=exp.eval(&self.symbols).map_err(|err| match err.span() {
+                    Some(_) => eval_error(err),
+                    None => SemaError::ConstInit(ident.to_string(), err).at(exp.span),
+                })?;
+                exp=Expr::new(ExprKind::Lit(value), exp.span);
+            }
+            values.push(exp);
+        }
+        Ok(values)
+    }
+
+    /// 检查表达式, 把它的类型填进去并返回
+    fn check(&mut self, expr: &mut Expr) -> Result<Ty, Diagnostic> {
+        let span=expr.span;
+        let ty=match &mut expr.kind {
+            ExprKind::Lit(_) => Ty::Int,
+            ExprKind::Str(_) => Ty::Str,
+            ExprKind::Unary(_, exp) => {
+                self.check_int(exp)?;
+                Ty::Int
+            }
+            ExprKind::Binary(_, lhs, rhs) => {
+                self.check_int(lhs)?;
+                self.check_int(rhs)?;
+                Ty::Int
+            }
+            ExprKind::Var(ident) => self.lval(ident, &mut [], false, span)?,
+            ExprKind::Index(ident, indices) => self.lval(ident, indices, false, span)?,
+            ExprKind::Call(ident, args) => self.call(ident, args, span)?,
+        };
+        expr.ty=Some(ty.clone());
+        Ok(ty)
+    }
+
+    /// 检查一个要当成 int 用的表达式
+    fn check_int(&mut self, expr: &mut Expr) -> Result<(), Diagnostic> {
+        let ty=self.check(expr)?;
+        expect_int(expr, ty)
+    }
+
+    /// 检查左值, 返回它作为表达式的类型; assign 表示这个左值要被赋值
+    fn lval(&mut self, ident: &str, indices: &mut [Expr], assign: bool, span: Span) -> Result<Ty, Diagnostic> {
+        for index in indices.iter_mut() {
+            self.check_int(index)?;
+        }
+        let ty=match self.symbols.lookup(ident) {
+            Some(Def::Const(_)) | Some(Def::ConstArray { .. }) if assign => return Err(SemaError::AssignToConst(ident.to_string()).at(span)),
+            Some(Def::Const(_)) => Ty::Int,
+            Some(Def::ConstArray { dims, .. }) => Ty::Array(dims.clone()),
+            Some(Def::Var(ty)) => ty.clone(),
+            Some(Def::Func { .. }) => return Err(SemaError::NotAVariable(ident.to_string()).at(span)),
+            None => return Err(self.undeclared(ident, span)),
+        };
+        index_ty(&ty, indices.len()).ok_or_else(|| SemaError::TooManyIndices(ident.to_string()).at(span))
+    }
+
+    /// 检查函数调用, 返回调用结果的类型
+    fn call(&mut self, ident: &str, args: &mut [Expr], span: Span) -> Result<Ty, Diagnostic> {
+        let (ret, params, variadic)=match self.symbols.lookup(ident) {
+            Some(Def::Func { ret, params, variadic }) => (ret.clone(), params.clone(), *variadic),
+            Some(_) => return Err(SemaError::NotAFunction(ident.to_string()).at(span)),
+            None => return Err(self.undeclared(ident, span)),
+        };
+        // 可变参数的只有 putf: 格式串后面跟着不超过 PUTF_ARGS 个 int
+        if variadic {
+            let (fmt, args)=match args.split_first_mut() {
+                Some((fmt, args)) if args.len()<params.len() => (fmt, args),
+                _ => return Err(SemaError::PutfArgs.at(span)),
+            };
+            if self.check(fmt)?!=params[0] {
+                return Err(SemaError::PutfArgs.at(span));
+            }
+            for arg in args.iter_mut() {
+                self.check_int(arg)?;
+            }
+            return Ok(ret);
+        }
+        if params.len()!=args.len() {
+            return Err(SemaError::ArgCount(ident.to_string(), params.len(), args.len()).at(span));
+        }
+        for (i, (arg, param)) in args.iter_mut().zip(params).enumerate() {
+            let ty=self.check(arg)?;
+            // void 函数的调用结果不能当参数
+            if ty==Ty::Void {
+                expect_int(arg, ty)?;
+            }
+            else if ty!=param {
+                return Err(SemaError::ArgType(ident.to_string(), i, param, ty).at(arg.span));
+            }
+        }
+        Ok(ret)
+    }
+
+    /// 未声明的名字. 跳过了出错的代码时它可能就声明在被跳过的地方,
+    /// 这时再报错只会误导, 记下来以后不再报告
+    fn undeclared(&mut self, ident: &str, span: Span) -> Diagnostic {
+        self.cascade=self.recovered;
+        SemaError::Undeclared(ident.to_string()).at(span)
+    }
+
+    /// 检查块里的每一项, 不新建作用域
+    fn block_items(&mut self, block: &mut Block) -> Result<(), Diagnostic> {
+        walk_block_mut(self, block)
+    }
+}
+
+/// 常量表达式里除以 0 或者下标越界时直接指出是哪个除法或下标,
+/// 而不是笼统地说初始值或数组长度不对
+fn eval_error(err: EvalError) -> Diagnostic {
+    let span=err.span().unwrap();
+    Diagnostic::new(err, span)
+}
+
+/// 类型为 ty 的表达式 expr 要当成 int 用, 不是 int 时报错
+fn expect_int(expr: &Expr, ty: Ty) -> Result<(), Diagnostic> {
+    let ident=match &expr.kind {
+        ExprKind::Var(ident) | ExprKind::Index(ident, _) | ExprKind::Call(ident, _) => ident.clone(),
+        _ => String::new(),
+    };
+    match ty {
+        Ty::Int => Ok(()),
+        Ty::Void => Err(SemaError::VoidValue(ident).at(expr.span)),
+        Ty::Str => Err(SemaError::StringLiteral.at(expr.span)),
+        Ty::Array(_) | Ty::Ptr(_) => Err(SemaError::ArrayValue(ident).at(expr.span)),
+    }
+}
+
+/// 对类型为 ty 的左值取 n 次下标后作为表达式的类型, 下标太多时为 None.
+/// 取到元素时是 int, 取到的还是数组时退化成指向它第一个元素的指针
+fn index_ty(ty: &Ty, n: usize) -> Option<Ty> {
+    match ty {
+        Ty::Int if n==0 => Some(Ty::Int),
+        Ty::Array(dims) if n==dims.len() => Some(Ty::Int),
+        Ty::Array(dims) if n<dims.len() => Some(Ty::Ptr(dims[n+1..].to_vec())),
+        // 数组参数本身就是指针, 相当于省略了第一维的数组
+        Ty::Ptr(_) if n==0 => Some(ty.clone()),
+        Ty::Ptr(dims) => index_ty(&Ty::Array(dims.clone()), n-1),
+        _ => None,
+    }
+}
+
+/// ConstInitVal 和 InitVal 按同样的规则展开
+pub trait InitList: Sized + Spanned {
+    /// 拆成单个表达式 (Ok) 或者初始化列表 (Err)
+    fn split(self) -> Result<Expr, Vec<Self>>;
+    /// 展开后的初始化列表
+    fn flat(exps: Vec<Expr>, span: Span) -> Self;
+    /// 这一项是单个表达式时返回它
+    fn exp(&self) -> Option<&Expr>;
+    /// 这一项是初始化列表时返回其中的每一项
+    fn list(&self) -> &[Self];
+
+    /// sema 展开以后的初始值里按顺序排列的每个元素
+    fn exps(&self) -> Vec<&Expr> {
+        match self.exp() {
+            Some(exp) => vec![exp],
+            None => self.list().iter().filter_map(|init| init.exp()).collect(),
+        }
+    }
+}
+
+macro_rules! impl_init_list {
+    ($($init: ty),*) => {
+        $(impl InitList for $init {
+            fn split(self) -> Result<Expr, Vec<Self>> {
+                match self {
+                    Self::Exp(exp) => Ok(exp),
+                    Self::List(list, _) => Err(list),
+                }
+            }
+
+            fn flat(exps: Vec<Expr>, span: Span) -> Self {
+                Self::List(exps.into_iter().map(Self::Exp).collect(), span)
+            }
+
+            fn exp(&self) -> Option<&Expr> {
+                match self {
+                    Self::Exp(exp) => Some(exp),
+                    Self::List(..) => None,
+                }
+            }
+
+            fn list(&self) -> &[Self] {
+                match self {
+                    Self::Exp(_) => &[],
+                    Self::List(list, _) => list,
+                }
+            }
+        })*
+    };
+}
+
+impl_init_list!(ConstInitVal, InitVal);
+
+/// 展开一层初始化列表, 填满 dims 大小的一块:
+/// 表达式依次填入下一个元素; 嵌套的列表填满当前位置对齐到的最大的一个子数组,
+/// 子数组的维度不包括 dims 的第一维; 最后没填到的元素补零 (None)
+fn flatten<T: InitList>(ident: &str, list: Vec<T>, dims: &[usize], exps: &mut Vec<Option<Expr>>) -> Result<(), Diagnostic> {
+    let start=exps.len();
+    let total: usize=dims.iter().product();
+    for init in list {
+        let offset=exps.len()-start;
+        let span=init.span();
+        if offset==total {
+            return Err(SemaError::BadInit(ident.to_string()).at(span));
+        }
+        match init.split() {
+            Ok(exp) => exps.push(Some(exp)),
+            Err(list) => {
+                let sub=(1..dims.len())
+                    .find(|&sub| offset.is_multiple_of(dims[sub..].iter().product::<usize>()))
+                    .ok_or_else(|| SemaError::BadInit(ident.to_string()).at(span))?;
+                flatten(ident, list, &dims[sub..], exps)?;
+            }
+        }
+    }
+    exps.resize_with(start+total, || None);
+    Ok(())
+}
+
+impl VisitorMut for Sema {
+    type Error = Diagnostic;
+
+    fn visit_comp_unit_mut(&mut self, comp_unit: &mut CompUnit) -> Result<(), Diagnostic> {
+        self.declare_library();
+        match walk_comp_unit_mut(self, comp_unit) {
+            // 真正的语法错误已经报告过了, 后面的检查也没有意义, 直接停下
+            Err(_) if self.cascade => Ok(()),
+            result => result,
+        }
+    }
+
+    fn visit_const_def_mut(&mut self, def: &mut ConstDef) -> Result<(), Diagnostic> {
+        let dims=self.array_dims(&def.ident, &mut def.dims)?;
+        let init=std::mem::replace(&mut def.constinitval, ConstInitVal::List(Vec::new(), def.span));
+        let span=init.span();
+        let exps=self.init_exps(&def.ident, init, &dims, true)?;
+        def.constinitval=if dims.is_empty() { ConstInitVal::Exp(exps.into_iter().next().unwrap()) } else { ConstInitVal::flat(exps, span) };
+        let values=def.constinitval.exps().iter().map(|exp| match exp.kind {
+            ExprKind::Lit(value) => value,
+            _ => unreachable!(),
+        }).collect::<Vec<_>>();
+        def.ty=if dims.is_empty() { Ty::Int } else { Ty::Array(dims.clone()) };
+        let symbol=if dims.is_empty() { Def::Const(values[0]) } else { Def::ConstArray { dims, values } };
+        self.define(&def.ident, symbol, def.span)
+    }
+
+    fn visit_var_def_mut(&mut self, def: &mut VarDef) -> Result<(), Diagnostic> {
+        let dims=self.array_dims(&def.ident, &mut def.dims)?;
+        if let Some(init)=def.initval.take() {
+            let span=init.span();
+            // 全局变量的初始值必须是常量表达式
+            let exps=self.init_exps(&def.ident, init, &dims, self.symbols.is_global())?;
+            def.initval=Some(if dims.is_empty() { InitVal::Exp(exps.into_iter().next().unwrap()) } else { InitVal::flat(exps, span) });
+        }
+        def.ty=if dims.is_empty() { Ty::Int } else { Ty::Array(dims) };
+        self.define(&def.ident, Def::Var(def.ty.clone()), def.span)
+    }
+
+    fn visit_func_def_mut(&mut self, func_def: &mut FuncDef) -> Result<(), Diagnostic> {
+        // 先定义函数名, 函数体里才能递归调用
+        let void=matches!(func_def.func_type, FuncType::Void);
+        let mut params=Vec::new();
+        for param in func_def.params.iter_mut() {
+            param.ty=match &mut param.dims {
+                Some(dims) => Ty::Ptr(self.array_dims(&param.ident, dims)?),
+                None => Ty::Int,
+            };
+            params.push(param.ty.clone());
+        }
+        let ret=if void { Ty::Void } else { Ty::Int };
+        self.define(&func_def.ident, Def::Func { ret, params, variadic: false }, func_def.span)?;
+        self.func=func_def.ident.clone();
+        self.func_void=void;
+        // 参数和函数体最外层的声明在同一个作用域里
+        self.symbols.push_scope();
+        for param in func_def.params.iter() {
+            self.define(&param.ident, Def::Var(param.ty.clone()), param.span)?;
+        }
+        self.block_items(&mut func_def.block)?;
+        self.symbols.pop_scope();
+        Ok(())
+    }
+
+    fn visit_block_mut(&mut self, block: &mut Block) -> Result<(), Diagnostic> {
+        self.symbols.push_scope();
+        self.block_items(block)?;
+        self.symbols.pop_scope();
+        Ok(())
+    }
+
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) -> Result<(), Diagnostic> {
+        match stmt {
+            Stmt::Assign(lval, exp, _) => {
+                if self.lval(&lval.ident, &mut lval.indices, true, lval.span)?!=Ty::Int {
+                    return Err(SemaError::ArrayValue(lval.ident.clone()).at(lval.span));
+                }
+                self.check_int(exp)
+            }
+            Stmt::Exp(exp, _) => match exp {
+                // 单独的函数调用语句可以调用 void 函数
+                Some(exp) if matches!(exp.kind, ExprKind::Call(..)) => self.check(exp).map(|_| ()),
+                Some(exp) => self.check_int(exp),
+                None => Ok(()),
+            }
+            Stmt::Return(exp, span) => match exp {
+                Some(_) if self.func_void => Err(SemaError::ReturnValueInVoid(self.func.clone()).at(*span)),
+                Some(exp) => self.check_int(exp),
+                None if !self.func_void => Err(SemaError::MissingReturnValue(self.func.clone()).at(*span)),
+                None => Ok(()),
+            }
+            Stmt::Block(block) => self.visit_block_mut(block),
+            Stmt::If(cond, then, els, _) => {
+                self.check_int(cond)?;
+                self.visit_stmt_mut(then)?;
+                match els {
+                    Some(els) => self.visit_stmt_mut(els),
+                    None => Ok(()),
+                }
+            }
+            Stmt::While(cond, body, _) => {
+                self.check_int(cond)?;
+                self.loops+=1;
+                self.visit_stmt_mut(body)?;
+                self.loops-=1;
+                Ok(())
+            }
+            Stmt::Break(span) if self.loops==0 => Err(SemaError::BreakOutsideLoop.at(*span)),
+            Stmt::Continue(span) if self.loops==0 => Err(SemaError::ContinueOutsideLoop.at(*span)),
+            Stmt::Break(_) | Stmt::Continue(_) => Ok(()),
+        }
+    }
+}
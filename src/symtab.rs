@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+
+/// 按作用域嵌套的符号表, 最外层是全局作用域.
+/// 语义分析和 IR 生成各自记录名字对应的不同信息
+pub struct SymbolTable<T> {
+    scopes: Vec<HashMap<String, T>>,
+}
+
+impl<T> Default for SymbolTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> SymbolTable<T> {
+    pub fn new() -> Self {
+        SymbolTable { scopes: vec![HashMap::new()] }
+    }
+
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    pub fn pop_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    /// 在当前作用域定义一个名字, 如果当前作用域已经有同名定义则返回 false
+    pub fn insert(&mut self, ident: &str, symbol: T) -> bool {
+        let scope=self.scopes.last_mut().unwrap();
+        if scope.contains_key(ident) {
+            return false;
+        }
+        scope.insert(ident.to_string(), symbol);
+        true
+    }
+
+    /// 当前是否在全局作用域
+    pub fn is_global(&self) -> bool {
+        self.scopes.len()==1
+    }
+
+    /// 从内向外查找一个名字
+    pub fn lookup(&self, ident: &str) -> Option<&T> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(ident))
+    }
+}
@@ -0,0 +1,332 @@
+use crate::ast::*;
+
+// 遍历 AST 的通用框架.
+// 每个 visit_* 方法默认调用同名的 walk_*, 依次访问这个节点的所有子节点;
+// 一个分析只需要重写它关心的节点, 在重写的方法里需要继续往下走时自己调用 walk_*.
+// 遇到错误时可以提前返回, 不会出错的分析把 Error 设成 std::convert::Infallible
+
+/// 只读地遍历 AST
+pub trait Visitor: Sized {
+    type Error;
+
+    fn visit_comp_unit(&mut self, comp_unit: &CompUnit) -> Result<(), Self::Error> {
+        walk_comp_unit(self, comp_unit)
+    }
+
+    fn visit_global_item(&mut self, item: &GlobalItem) -> Result<(), Self::Error> {
+        walk_global_item(self, item)
+    }
+
+    fn visit_decl(&mut self, decl: &Decl) -> Result<(), Self::Error> {
+        walk_decl(self, decl)
+    }
+
+    fn visit_const_def(&mut self, def: &ConstDef) -> Result<(), Self::Error> {
+        walk_const_def(self, def)
+    }
+
+    fn visit_const_init_val(&mut self, init: &ConstInitVal) -> Result<(), Self::Error> {
+        walk_const_init_val(self, init)
+    }
+
+    fn visit_var_def(&mut self, def: &VarDef) -> Result<(), Self::Error> {
+        walk_var_def(self, def)
+    }
+
+    fn visit_init_val(&mut self, init: &InitVal) -> Result<(), Self::Error> {
+        walk_init_val(self, init)
+    }
+
+    fn visit_func_def(&mut self, func_def: &FuncDef) -> Result<(), Self::Error> {
+        walk_func_def(self, func_def)
+    }
+
+    fn visit_func_fparam(&mut self, param: &FuncFParam) -> Result<(), Self::Error> {
+        walk_func_fparam(self, param)
+    }
+
+    fn visit_block(&mut self, block: &Block) -> Result<(), Self::Error> {
+        walk_block(self, block)
+    }
+
+    fn visit_block_item(&mut self, item: &BlockItem) -> Result<(), Self::Error> {
+        walk_block_item(self, item)
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt) -> Result<(), Self::Error> {
+        walk_stmt(self, stmt)
+    }
+
+    fn visit_lval(&mut self, lval: &LVal) -> Result<(), Self::Error> {
+        walk_lval(self, lval)
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) -> Result<(), Self::Error> {
+        walk_expr(self, expr)
+    }
+}
+
+pub fn walk_comp_unit<V: Visitor>(v: &mut V, comp_unit: &CompUnit) -> Result<(), V::Error> {
+    comp_unit.items.iter().try_for_each(|item| v.visit_global_item(item))
+}
+
+pub fn walk_global_item<V: Visitor>(v: &mut V, item: &GlobalItem) -> Result<(), V::Error> {
+    match item {
+        GlobalItem::Decl(decl) => v.visit_decl(decl),
+        GlobalItem::FuncDef(func_def) => v.visit_func_def(func_def),
+    }
+}
+
+pub fn walk_decl<V: Visitor>(v: &mut V, decl: &Decl) -> Result<(), V::Error> {
+    match decl {
+        Decl::CDecl(constdecl) => constdecl.constdefs.iter().try_for_each(|def| v.visit_const_def(def)),
+        Decl::VDecl(vardecl) => vardecl.vardefs.iter().try_for_each(|def| v.visit_var_def(def)),
+    }
+}
+
+pub fn walk_const_def<V: Visitor>(v: &mut V, def: &ConstDef) -> Result<(), V::Error> {
+    def.dims.iter().try_for_each(|dim| v.visit_expr(dim))?;
+    v.visit_const_init_val(&def.constinitval)
+}
+
+pub fn walk_const_init_val<V: Visitor>(v: &mut V, init: &ConstInitVal) -> Result<(), V::Error> {
+    match init {
+        ConstInitVal::Exp(exp) => v.visit_expr(exp),
+        ConstInitVal::List(list, _) => list.iter().try_for_each(|init| v.visit_const_init_val(init)),
+    }
+}
+
+pub fn walk_var_def<V: Visitor>(v: &mut V, def: &VarDef) -> Result<(), V::Error> {
+    def.dims.iter().try_for_each(|dim| v.visit_expr(dim))?;
+    match &def.initval {
+        Some(init) => v.visit_init_val(init),
+        None => Ok(()),
+    }
+}
+
+pub fn walk_init_val<V: Visitor>(v: &mut V, init: &InitVal) -> Result<(), V::Error> {
+    match init {
+        InitVal::Exp(exp) => v.visit_expr(exp),
+        InitVal::List(list, _) => list.iter().try_for_each(|init| v.visit_init_val(init)),
+    }
+}
+
+pub fn walk_func_def<V: Visitor>(v: &mut V, func_def: &FuncDef) -> Result<(), V::Error> {
+    func_def.params.iter().try_for_each(|param| v.visit_func_fparam(param))?;
+    v.visit_block(&func_def.block)
+}
+
+pub fn walk_func_fparam<V: Visitor>(v: &mut V, param: &FuncFParam) -> Result<(), V::Error> {
+    param.dims.iter().flatten().try_for_each(|dim| v.visit_expr(dim))
+}
+
+pub fn walk_block<V: Visitor>(v: &mut V, block: &Block) -> Result<(), V::Error> {
+    block.items.iter().try_for_each(|item| v.visit_block_item(item))
+}
+
+pub fn walk_block_item<V: Visitor>(v: &mut V, item: &BlockItem) -> Result<(), V::Error> {
+    match item {
+        BlockItem::Decl(decl) => v.visit_decl(decl),
+        BlockItem::Stmt(stmt) => v.visit_stmt(stmt),
+    }
+}
+
+pub fn walk_stmt<V: Visitor>(v: &mut V, stmt: &Stmt) -> Result<(), V::Error> {
+    match stmt {
+        Stmt::Assign(lval, exp, _) => {
+            v.visit_lval(lval)?;
+            v.visit_expr(exp)
+        }
+        Stmt::Exp(exp, _) | Stmt::Return(exp, _) => exp.iter().try_for_each(|exp| v.visit_expr(exp)),
+        Stmt::Block(block) => v.visit_block(block),
+        Stmt::If(cond, then, els, _) => {
+            v.visit_expr(cond)?;
+            v.visit_stmt(then)?;
+            els.iter().try_for_each(|els| v.visit_stmt(els))
+        }
+        Stmt::While(cond, body, _) => {
+            v.visit_expr(cond)?;
+            v.visit_stmt(body)
+        }
+        Stmt::Break(_) | Stmt::Continue(_) => Ok(()),
+    }
+}
+
+pub fn walk_lval<V: Visitor>(v: &mut V, lval: &LVal) -> Result<(), V::Error> {
+    lval.indices.iter().try_for_each(|index| v.visit_expr(index))
+}
+
+pub fn walk_expr<V: Visitor>(v: &mut V, expr: &Expr) -> Result<(), V::Error> {
+    match &expr.kind {
+        ExprKind::Binary(_, lhs, rhs) => {
+            v.visit_expr(lhs)?;
+            v.visit_expr(rhs)
+        }
+        ExprKind::Unary(_, exp) => v.visit_expr(exp),
+        ExprKind::Call(_, args) | ExprKind::Index(_, args) => args.iter().try_for_each(|arg| v.visit_expr(arg)),
+        ExprKind::Lit(_) | ExprKind::Str(_) | ExprKind::Var(_) => Ok(()),
+    }
+}
+
+/// 遍历 AST 并就地修改节点
+pub trait VisitorMut: Sized {
+    type Error;
+
+    fn visit_comp_unit_mut(&mut self, comp_unit: &mut CompUnit) -> Result<(), Self::Error> {
+        walk_comp_unit_mut(self, comp_unit)
+    }
+
+    fn visit_global_item_mut(&mut self, item: &mut GlobalItem) -> Result<(), Self::Error> {
+        walk_global_item_mut(self, item)
+    }
+
+    fn visit_decl_mut(&mut self, decl: &mut Decl) -> Result<(), Self::Error> {
+        walk_decl_mut(self, decl)
+    }
+
+    fn visit_const_def_mut(&mut self, def: &mut ConstDef) -> Result<(), Self::Error> {
+        walk_const_def_mut(self, def)
+    }
+
+    fn visit_const_init_val_mut(&mut self, init: &mut ConstInitVal) -> Result<(), Self::Error> {
+        walk_const_init_val_mut(self, init)
+    }
+
+    fn visit_var_def_mut(&mut self, def: &mut VarDef) -> Result<(), Self::Error> {
+        walk_var_def_mut(self, def)
+    }
+
+    fn visit_init_val_mut(&mut self, init: &mut InitVal) -> Result<(), Self::Error> {
+        walk_init_val_mut(self, init)
+    }
+
+    fn visit_func_def_mut(&mut self, func_def: &mut FuncDef) -> Result<(), Self::Error> {
+        walk_func_def_mut(self, func_def)
+    }
+
+    fn visit_func_fparam_mut(&mut self, param: &mut FuncFParam) -> Result<(), Self::Error> {
+        walk_func_fparam_mut(self, param)
+    }
+
+    fn visit_block_mut(&mut self, block: &mut Block) -> Result<(), Self::Error> {
+        walk_block_mut(self, block)
+    }
+
+    fn visit_block_item_mut(&mut self, item: &mut BlockItem) -> Result<(), Self::Error> {
+        walk_block_item_mut(self, item)
+    }
+
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) -> Result<(), Self::Error> {
+        walk_stmt_mut(self, stmt)
+    }
+
+    fn visit_lval_mut(&mut self, lval: &mut LVal) -> Result<(), Self::Error> {
+        walk_lval_mut(self, lval)
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) -> Result<(), Self::Error> {
+        walk_expr_mut(self, expr)
+    }
+}
+
+pub fn walk_comp_unit_mut<V: VisitorMut>(v: &mut V, comp_unit: &mut CompUnit) -> Result<(), V::Error> {
+    comp_unit.items.iter_mut().try_for_each(|item| v.visit_global_item_mut(item))
+}
+
+pub fn walk_global_item_mut<V: VisitorMut>(v: &mut V, item: &mut GlobalItem) -> Result<(), V::Error> {
+    match item {
+        GlobalItem::Decl(decl) => v.visit_decl_mut(decl),
+        GlobalItem::FuncDef(func_def) => v.visit_func_def_mut(func_def),
+    }
+}
+
+pub fn walk_decl_mut<V: VisitorMut>(v: &mut V, decl: &mut Decl) -> Result<(), V::Error> {
+    match decl {
+        Decl::CDecl(constdecl) => constdecl.constdefs.iter_mut().try_for_each(|def| v.visit_const_def_mut(def)),
+        Decl::VDecl(vardecl) => vardecl.vardefs.iter_mut().try_for_each(|def| v.visit_var_def_mut(def)),
+    }
+}
+
+pub fn walk_const_def_mut<V: VisitorMut>(v: &mut V, def: &mut ConstDef) -> Result<(), V::Error> {
+    def.dims.iter_mut().try_for_each(|dim| v.visit_expr_mut(dim))?;
+    v.visit_const_init_val_mut(&mut def.constinitval)
+}
+
+pub fn walk_const_init_val_mut<V: VisitorMut>(v: &mut V, init: &mut ConstInitVal) -> Result<(), V::Error> {
+    match init {
+        ConstInitVal::Exp(exp) => v.visit_expr_mut(exp),
+        ConstInitVal::List(list, _) => list.iter_mut().try_for_each(|init| v.visit_const_init_val_mut(init)),
+    }
+}
+
+pub fn walk_var_def_mut<V: VisitorMut>(v: &mut V, def: &mut VarDef) -> Result<(), V::Error> {
+    def.dims.iter_mut().try_for_each(|dim| v.visit_expr_mut(dim))?;
+    match &mut def.initval {
+        Some(init) => v.visit_init_val_mut(init),
+        None => Ok(()),
+    }
+}
+
+pub fn walk_init_val_mut<V: VisitorMut>(v: &mut V, init: &mut InitVal) -> Result<(), V::Error> {
+    match init {
+        InitVal::Exp(exp) => v.visit_expr_mut(exp),
+        InitVal::List(list, _) => list.iter_mut().try_for_each(|init| v.visit_init_val_mut(init)),
+    }
+}
+
+pub fn walk_func_def_mut<V: VisitorMut>(v: &mut V, func_def: &mut FuncDef) -> Result<(), V::Error> {
+    func_def.params.iter_mut().try_for_each(|param| v.visit_func_fparam_mut(param))?;
+    v.visit_block_mut(&mut func_def.block)
+}
+
+pub fn walk_func_fparam_mut<V: VisitorMut>(v: &mut V, param: &mut FuncFParam) -> Result<(), V::Error> {
+    param.dims.iter_mut().flatten().try_for_each(|dim| v.visit_expr_mut(dim))
+}
+
+pub fn walk_block_mut<V: VisitorMut>(v: &mut V, block: &mut Block) -> Result<(), V::Error> {
+    block.items.iter_mut().try_for_each(|item| v.visit_block_item_mut(item))
+}
+
+pub fn walk_block_item_mut<V: VisitorMut>(v: &mut V, item: &mut BlockItem) -> Result<(), V::Error> {
+    match item {
+        BlockItem::Decl(decl) => v.visit_decl_mut(decl),
+        BlockItem::Stmt(stmt) => v.visit_stmt_mut(stmt),
+    }
+}
+
+pub fn walk_stmt_mut<V: VisitorMut>(v: &mut V, stmt: &mut Stmt) -> Result<(), V::Error> {
+    match stmt {
+        Stmt::Assign(lval, exp, _) => {
+            v.visit_lval_mut(lval)?;
+            v.visit_expr_mut(exp)
+        }
+        Stmt::Exp(exp, _) | Stmt::Return(exp, _) => exp.iter_mut().try_for_each(|exp| v.visit_expr_mut(exp)),
+        Stmt::Block(block) => v.visit_block_mut(block),
+        Stmt::If(cond, then, els, _) => {
+            v.visit_expr_mut(cond)?;
+            v.visit_stmt_mut(then)?;
+            els.iter_mut().try_for_each(|els| v.visit_stmt_mut(els))
+        }
+        Stmt::While(cond, body, _) => {
+            v.visit_expr_mut(cond)?;
+            v.visit_stmt_mut(body)
+        }
+        Stmt::Break(_) | Stmt::Continue(_) => Ok(()),
+    }
+}
+
+pub fn walk_lval_mut<V: VisitorMut>(v: &mut V, lval: &mut LVal) -> Result<(), V::Error> {
+    lval.indices.iter_mut().try_for_each(|index| v.visit_expr_mut(index))
+}
+
+pub fn walk_expr_mut<V: VisitorMut>(v: &mut V, expr: &mut Expr) -> Result<(), V::Error> {
+    match &mut expr.kind {
+        ExprKind::Binary(_, lhs, rhs) => {
+            v.visit_expr_mut(lhs)?;
+            v.visit_expr_mut(rhs)
+        }
+        ExprKind::Unary(_, exp) => v.visit_expr_mut(exp),
+        ExprKind::Call(_, args) | ExprKind::Index(_, args) => args.iter_mut().try_for_each(|arg| v.visit_expr_mut(arg)),
+        ExprKind::Lit(_) | ExprKind::Str(_) | ExprKind::Var(_) => Ok(()),
+    }
+}